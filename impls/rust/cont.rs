@@ -0,0 +1,215 @@
+use crate::env::Env;
+use crate::sync::Shared;
+use crate::types::{MalErr, Value};
+
+// The frames of the evaluator's stack
+//
+// eval does not recurse on the Rust stack to evaluate the forms inside a
+// form. It pushes a frame saying what to do with the value of the inner form
+// and goes on with that form, so that everything left to do is on the stack
+// as data. shift takes the frames up to its reset off the stack as a
+// continuation, and calling the continuation pushes copies of them back.
+
+// The loop* that recur goes back to
+pub struct Loop {
+    pub env: Env,
+    pub syms: Vec<Value>,
+    pub body: Value,
+    // Whether each iteration needs an env of its own, see may_capture
+    pub fresh: bool,
+}
+
+// The loop* a form is the tail of, if any
+pub type Tail = Option<Shared<Loop>>;
+
+// What the values of the forms a Seq frame evaluates are for
+#[derive(Clone)]
+pub enum SeqKind {
+    // A function call, the function then the arguments
+    Call,
+    // A list, vector or map literal, with the metadata it was read with
+    List(Option<Value>),
+    Vec(Option<Value>),
+    Map(Vec<String>, Option<Value>),
+    // The new values of the loop variables
+    Recur(Shared<Loop>),
+    // The values of the dynamic vars, by qualified name, then the body
+    Binding(Vec<String>, Value),
+}
+
+#[derive(Clone)]
+pub enum Frame {
+    // Evaluates forms one at a time, `todo` holds the ones left in reverse
+    Seq {
+        kind: SeqKind,
+        done: Vec<Value>,
+        todo: Vec<Value>,
+        env: Env,
+    },
+    // Waits for the condition of an if
+    If {
+        then: Value,
+        otherwise: Value,
+        env: Env,
+        tail: Tail,
+    },
+    // Evaluates the forms of a do, `forms` holds the ones left in reverse
+    Do {
+        forms: Vec<Value>,
+        env: Env,
+        tail: Tail,
+    },
+    // Waits for the value of a let* binding, `binds` holds the pairs left in reverse
+    Let {
+        env: Env,
+        sym: Value,
+        binds: Vec<(Value, Value)>,
+        body: Value,
+        tail: Tail,
+    },
+    // The same for loop*, which then starts the loop with the variables
+    Loop {
+        env: Env,
+        sym: Value,
+        binds: Vec<(Value, Value)>,
+        syms: Vec<Value>,
+        body: Value,
+    },
+    // Waits for the value of a def!
+    Def {
+        sym: Value,
+        meta: Value,
+        form: Value,
+        env: Env,
+    },
+    // Waits for the form given to eval
+    Eval,
+    // The dynamic vars a binding form binds while its body runs, by
+    // qualified name
+    Binding {
+        vars: Vec<(String, Value)>,
+    },
+    // The handlers a handler-bind establishes while its body runs
+    Handlers(Vec<(Value, Value)>),
+    // The restarts a restart-case establishes while its expression runs,
+    // numbered from `first`, with their clauses
+    Restarts {
+        names: Vec<String>,
+        first: usize,
+        clauses: Vec<Value>,
+        env: Env,
+    },
+    // Catches what the body of a try* throws
+    Try {
+        catches: Vec<Vec<Value>>,
+        finally: Option<Value>,
+        env: Env,
+    },
+    // Waits for a finally* body, then gives what the try* was going to
+    Finally(Result<Value, MalErr>),
+    // Where a reset starts, which shift captures up to
+    Reset,
+}
+
+impl Frame {
+    // Calls f with the values the frame holds, and envs with its envs, for
+    // the cycle collector. Loops are shared between frames, so they are left
+    // out, which makes the collector take what they hold as used from outside.
+    pub fn visit(&self, f: &mut dyn FnMut(&Value), envs: &mut dyn FnMut(&Env)) {
+        match self {
+            Frame::Seq {
+                kind,
+                done,
+                todo,
+                env,
+            } => {
+                match kind {
+                    SeqKind::List(Some(meta))
+                    | SeqKind::Vec(Some(meta))
+                    | SeqKind::Map(_, Some(meta)) => f(meta),
+                    SeqKind::Binding(_, body) => f(body),
+                    _ => (),
+                }
+                done.iter().chain(todo.iter()).for_each(&mut *f);
+                envs(env);
+            }
+            Frame::If {
+                then,
+                otherwise,
+                env,
+                ..
+            } => {
+                f(then);
+                f(otherwise);
+                envs(env);
+            }
+            Frame::Do { forms, env, .. } => {
+                forms.iter().for_each(&mut *f);
+                envs(env);
+            }
+            Frame::Let {
+                env,
+                sym,
+                binds,
+                body,
+                ..
+            } => {
+                envs(env);
+                f(sym);
+                binds.iter().for_each(|(b, e)| {
+                    f(b);
+                    f(e);
+                });
+                f(body);
+            }
+            Frame::Loop {
+                env,
+                sym,
+                binds,
+                syms,
+                body,
+            } => {
+                envs(env);
+                f(sym);
+                binds.iter().for_each(|(b, e)| {
+                    f(b);
+                    f(e);
+                });
+                syms.iter().for_each(&mut *f);
+                f(body);
+            }
+            Frame::Def {
+                sym,
+                meta,
+                form,
+                env,
+            } => {
+                f(sym);
+                f(meta);
+                f(form);
+                envs(env);
+            }
+            Frame::Binding { vars } => vars.iter().for_each(|(_, v)| f(v)),
+            Frame::Handlers(handlers) => handlers.iter().for_each(|(m, h)| {
+                f(m);
+                f(h);
+            }),
+            Frame::Restarts { clauses, env, .. } => {
+                clauses.iter().for_each(&mut *f);
+                envs(env);
+            }
+            Frame::Try {
+                catches,
+                finally,
+                env,
+            } => {
+                catches.iter().flatten().for_each(&mut *f);
+                finally.iter().for_each(&mut *f);
+                envs(env);
+            }
+            Frame::Finally(Ok(v)) => f(v),
+            Frame::Finally(Err(MalErr::ErrMalVal(v))) => f(v),
+            Frame::Finally(_) | Frame::Eval | Frame::Reset => (),
+        }
+    }
+}
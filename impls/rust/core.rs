@@ -1,66 +1,235 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Read;
-use std::rc::Rc;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::DefaultEditor;
 
-use crate::printer::pr_seq;
+use fnv::FnvHashMap;
+use regex::Regex;
+
+use crate::env::{all_vars, in_ns, var_meta};
+use crate::gc::track_atom;
+use crate::limits::check_size;
+use crate::printer::{pr_seq, pr_str_pretty, print_limits, PrettyConfig, PrintLimits};
 use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use crate::sync::{Lock, Shared};
+use crate::types::MalErr::{ErrLimit, ErrMalVal, ErrString};
+use crate::types::Value::{Boolean, Char, List, Map, Null, Number, Symbol};
+use crate::types::{
+    _assoc, _dissoc, atom, error, func, hash_map, key_string, key_value, keyword, lazy_seq, list,
+    Atom, AtomCell, Channel, Lazy, MalArgs, MalErr, MalRet, Value, Waiter,
+};
+#[cfg(feature = "threads")]
+use crate::{
+    env::{bindings, current_ns, push_bindings, set_current_ns},
+    gc::Worker,
+    limits::{inherit, inherited, Inherited},
+    printer::set_print_limits,
+    types::Future,
+};
 
-macro_rules! fn_t_int_int {
+macro_rules! fn_t_num_num {
     ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+        |a: MalArgs| match (&a[0], &a[1]) {
+            (Number(a0), Number(a1)) => Ok($ret($fn(*a0, *a1))),
+            _ => error("expecting (number,number) args"),
         }
     }};
 }
 
 macro_rules! fn_is_type {
   ($($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $($ps => true,)* _ => false})) }
+    |a:MalArgs| { Ok(Boolean(match a[0].strip() { $($ps => true,)* _ => false})) }
   }};
   ($p:pat if $e:expr) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, _ => false})) }
+    |a:MalArgs| { Ok(Boolean(match a[0].strip() { $p if $e => true, _ => false})) }
   }};
   ($p:pat if $e:expr,$($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false})) }
+    |a:MalArgs| { Ok(Boolean(match a[0].strip() { $p if $e => true, $($ps => true,)* _ => false})) }
   }};
 }
 
 macro_rules! fn_str {
     ($fn:expr) => {{
-        |a: MalArgs| match a[0].clone() {
-            Str(a0) => $fn(a0),
+        |a: MalArgs| match a[0] {
+            Value::String(ref a0) => $fn(a0),
             _ => error("expecting (str) arg"),
         }
     }};
 }
 
+// Returns the number as an integer, when it is one
+pub fn int_value(v: &Value) -> Option<i64> {
+    match v {
+        Number(n) if n.fract() == 0.0 => Some(*n as i64),
+        _ => None,
+    }
+}
+
+fn vector(v: Vec<Value>) -> Value {
+    Value::Vec(v)
+}
+
+// Realizes the first `length` elements of a lazy seq, or all of them, so
+// that seq() returns its elements. Does nothing to other values. Stops at a
+// seq that was realized into a cycle, which the tortoise catches up with.
+fn realize_upto(v: &Value, length: Option<usize>) -> Result<(), MalErr> {
+    let mut next = v.clone();
+    let mut tortoise = v.clone();
+    let mut n = 0;
+    while let Value::Lazy(ref s) = next.strip() {
+        if length == Some(n) {
+            break;
+        }
+        if n > 0 && matches!(tortoise.strip(), Value::Lazy(ref t) if Shared::ptr_eq(s, t)) {
+            break;
+        }
+        n += 1;
+        check_size(n)?;
+        next = match next.uncons()? {
+            Some((_, rest)) => rest,
+            None => break,
+        };
+        if n % 2 == 0 {
+            tortoise = tortoise.uncons()?.map_or(Null, |(_, rest)| rest);
+        }
+    }
+    Ok(())
+}
+
+fn realize(v: &Value) -> Result<(), MalErr> {
+    realize_upto(v, None)
+}
+
+// Realizes the lazy seqs in the value as far as the limits print them, so
+// that printing and = see them. Atoms are left alone, they print their
+// contents as they are. `inside` holds the seqs being realized, so that a
+// seq holding itself is realized once.
+fn realize_deep(
+    v: &Value,
+    limits: &PrintLimits,
+    level: usize,
+    inside: &mut Vec<usize>,
+) -> Result<(), MalErr> {
+    if limits.level.is_some_and(|max| level >= max) {
+        return Ok(());
+    }
+    realize_upto(v, limits.length)?;
+    let elems = match v.strip() {
+        List(l) => l.iter().cloned().collect(),
+        Value::Vec(v) => v.clone(),
+        Map(hm) => hm.values().cloned().collect(),
+        Value::Lazy(s) if inside.contains(&(Shared::as_ptr(s) as usize)) => return Ok(()),
+        Value::Lazy(s) => {
+            inside.push(Shared::as_ptr(s) as usize);
+            let elems = s.realized(limits.length).0;
+            for elem in elems.iter() {
+                realize_deep(elem, limits, level + 1, inside)?;
+            }
+            inside.pop();
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    for elem in elems.iter().take(limits.length.unwrap_or(usize::MAX)) {
+        realize_deep(elem, limits, level + 1, inside)?;
+    }
+    Ok(())
+}
+
+// Realizes what the printing functions print of the values
+pub fn realize_printed(values: &[Value]) -> Result<(), MalErr> {
+    let limits = print_limits();
+    values
+        .iter()
+        .try_for_each(|v| realize_deep(v, &limits, 0, &mut vec![]))
+}
+
+// Prints the values like pr_seq for pr-str and str, checking the size of
+// the string before adding each one to it
+fn pr_sized(values: &[Value], readable: bool, join: &str) -> Result<String, MalErr> {
+    let mut s = String::new();
+    for (i, v) in values.iter().enumerate() {
+        let sep = if i == 0 { "" } else { join };
+        let printed = v.pr_str(readable);
+        check_size(s.len() + sep.len() + printed.len())?;
+        s.push_str(sep);
+        s.push_str(&printed);
+    }
+    Ok(s)
+}
+
+// Reads the first form of the string, nil when it has none
+pub fn read_string(s: &str) -> MalRet {
+    match read_str(s) {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => Ok(Null),
+        Err(e) => error(&e.to_string()),
+    }
+}
+
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
+        Value::String(ref s) => Ok(Symbol(s.to_string())),
         _ => error("illegal symbol call"),
     }
 }
 
+fn to_char(a: MalArgs) -> MalRet {
+    match (&a[0], int_value(&a[0])) {
+        (Char(c), _) => Ok(Char(*c)),
+        (_, Some(i)) => match u32::try_from(i).ok().and_then(char::from_u32) {
+            Some(c) => Ok(Char(c)),
+            None => error(&format!("char: {} is not a valid code point", i)),
+        },
+        _ => error("char: expecting (int) arg"),
+    }
+}
+
+fn int(a: MalArgs) -> MalRet {
+    match (&a[0], int_value(&a[0])) {
+        (Char(c), _) => Ok(Number(*c as u32 as f64)),
+        (_, Some(i)) => Ok(Number(i as f64)),
+        (Number(n), None) => Ok(Number(n.trunc())),
+        _ => error("int: expecting (char) arg"),
+    }
+}
+
+// Integer division when both sides are integers, as the tests expect
+fn divide(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Number(_), Number(d)) if *d == 0.0 => error("divide by zero"),
+        (Number(n), Number(d)) if n.fract() == 0.0 && d.fract() == 0.0 => {
+            Ok(Number((n / d).trunc()))
+        }
+        (Number(n), Number(d)) => Ok(Number(n / d)),
+        _ => error("expecting (number,number) args"),
+    }
+}
+
 fn readline(a: MalArgs) -> MalRet {
     lazy_static! {
-        static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
+        static ref RL: Mutex<Option<DefaultEditor>> = Mutex::new(DefaultEditor::new().ok());
     }
     //let mut rl = Editor::<()>::new();
 
     match a[0] {
-        Str(ref p) => {
+        Value::String(ref p) => {
+            let mut rl = RL.lock().unwrap();
+            let rl = match rl.as_mut() {
+                Some(rl) => rl,
+                None => return error("readline: no terminal"),
+            };
             //match rl.readline(p) {
-            match RL.lock().unwrap().readline(p) {
+            match rl.readline(p) {
                 Ok(mut line) => {
                     // Remove any trailing \n or \r\n
                     if line.ends_with('\n') {
@@ -69,9 +238,9 @@ fn readline(a: MalArgs) -> MalRet {
                             line.pop();
                         }
                     }
-                    Ok(Str(line))
+                    Ok(Value::String(line))
                 }
-                Err(ReadlineError::Eof) => Ok(Nil),
+                Err(ReadlineError::Eof) => Ok(Null),
                 Err(e) => error(&format!("{:?}", e)),
             }
         }
@@ -79,103 +248,186 @@ fn readline(a: MalArgs) -> MalRet {
     }
 }
 
-fn slurp(f: String) -> MalRet {
+fn slurp(f: &str) -> MalRet {
     let mut s = String::new();
-    match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
-        Ok(_) => Ok(Str(s)),
+    let mut file = match File::open(f) {
+        Ok(file) => file,
+        Err(e) => return error(&e.to_string()),
+    };
+    match file.read_to_string(&mut s) {
+        Ok(_) => Ok(Value::String(s)),
         Err(e) => error(&e.to_string()),
     }
 }
 
+// Regexes are kept as their pattern string, this only checks that it compiles
+fn re_pattern(a: MalArgs) -> MalRet {
+    match a[0] {
+        Value::String(ref p) => match Regex::new(p) {
+            Ok(_) => Ok(Value::String(p.to_string())),
+            Err(e) => error(&e.to_string()),
+        },
+        _ => error("re-pattern: pattern is not Str"),
+    }
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+    match (&a[0], &a[1]) {
+        (Value::String(ref p), Value::String(ref s)) => match Regex::new(p) {
+            Ok(re) => Ok(re
+                .find(s)
+                .map_or(Null, |m| Value::String(m.as_str().to_string()))),
+            Err(e) => error(&e.to_string()),
+        },
+        _ => error("re-find: expecting (str,str) args"),
+    }
+}
+
+// Pretty prints the first arg, with an optional width and indent after it
+fn pretty(a: &MalArgs) -> Result<String, MalErr> {
+    if a.is_empty() || a.len() > 3 {
+        return error("pprint: expected a value, and optionally a width and an indent");
+    }
+    realize_printed(&a[..1])?;
+    let mut config = PrettyConfig::default();
+    for (i, setting) in a.iter().enumerate().skip(1) {
+        let n = match int_value(setting) {
+            Some(n) if n >= 0 => n as usize,
+            _ => {
+                return Err(ErrString(
+                    "pprint: width and indent must be Int".to_string(),
+                ))
+            }
+        };
+        match i {
+            1 => config.width = n,
+            _ => config.indent = n,
+        }
+    }
+    Ok(pr_str_pretty(&a[0], &config))
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
         Err(e) => return error(&format!("{:?}", e)),
     };
-    Ok(Int(
-        ms_e.as_secs() as i64 * 1000 + ms_e.subsec_nanos() as i64 / 1_000_000
+    Ok(Number(
+        (ms_e.as_secs() as i64 * 1000 + ms_e.subsec_nanos() as i64 / 1_000_000) as f64,
     ))
 }
 
 fn get(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+    match (a[0].strip(), key_string(&a[1])) {
+        (Null, _) => Ok(Null),
+        (Map(ref hm), Ok(ref k)) => match hm.get(k) {
             Some(mv) => Ok(mv.clone()),
-            None => Ok(Nil),
+            None => Ok(Null),
         },
+        (Map(_), Err(_)) => Ok(Null),
         _ => error("illegal get args"),
     }
 }
 
 fn assoc(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
+    match a[0].strip() {
+        Map(ref hm) => {
+            check_size(hm.len() + (a.len() - 1) / 2)?;
+            _assoc(hm.clone(), a[1..].to_vec())
+        }
         _ => error("assoc on non-Hash Map"),
     }
 }
 
 fn dissoc(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
+    match a[0].strip() {
+        Map(ref hm) => _dissoc(hm.clone(), a[1..].to_vec()),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
 fn contains_q(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
+    match (a[0].strip(), key_string(&a[1])) {
+        (Map(ref hm), Ok(ref k)) => Ok(Boolean(hm.contains_key(k))),
+        (Map(_), Err(_)) => Ok(Boolean(false)),
         _ => error("illegal get args"),
     }
 }
 
 fn keys(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+    match a[0].strip() {
+        Map(ref hm) => Ok(list(hm.keys().map(|k| key_value(k)).collect())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vals(a: MalArgs) -> MalRet {
-    match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
+    match a[0].strip() {
+        Map(ref hm) => Ok(list(hm.values().cloned().collect())),
         _ => error("keys requires Hash Map"),
     }
 }
 
 fn vec(a: MalArgs) -> MalRet {
-    match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
-        _ => error("non-seq passed to vec"),
+    realize(&a[0])?;
+    match a[0].seq() {
+        Some(v) => {
+            check_size(v.len())?;
+            Ok(vector(v))
+        }
+        None => error("non-seq passed to vec"),
     }
 }
 
 fn cons(a: MalArgs) -> MalRet {
-    match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
+    // The rest of a lazy seq stays unrealized
+    if let Value::Lazy(_) = a[1].strip() {
+        return Ok(lazy_seq(Lazy::Cons(a[0].clone(), a[1].clone())));
+    }
+    match a[1].seq() {
+        Some(v) => {
+            check_size(v.len() + 1)?;
             let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+            new_v.extend(v);
+            Ok(list(new_v))
         }
-        _ => error("cons expects seq as second arg"),
+        None => error("cons expects seq as second arg"),
     }
 }
 
 fn concat(a: MalArgs) -> MalRet {
     let mut new_v = vec![];
     for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
+        realize(seq)?;
+        match seq.seq() {
+            Some(v) => {
+                check_size(new_v.len() + v.len())?;
+                new_v.extend(v)
+            }
+            None => return error("non-seq passed to concat"),
         }
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(list(new_v))
 }
 
 fn nth(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
-            if seq.len() <= idx as usize {
+    // A lazy seq is realized as far as the index
+    if let (Value::Lazy(_), Some(idx)) = (a[0].strip(), int_value(&a[1])) {
+        let mut next = a[0].clone();
+        for _ in 0..idx.max(0) {
+            next = match next.uncons()? {
+                Some((_, rest)) => rest,
+                None => break,
+            };
+        }
+        return match next.uncons()? {
+            Some((elem, _)) if idx >= 0 => Ok(elem),
+            _ => error("nth: index out of range"),
+        };
+    }
+    match (a[0].seq(), int_value(&a[1])) {
+        (Some(seq), Some(idx)) => {
+            if idx < 0 || seq.len() <= idx as usize {
                 return error("nth: index out of range");
             }
             Ok(seq[idx as usize].clone())
@@ -185,167 +437,1896 @@ fn nth(a: MalArgs) -> MalRet {
 }
 
 fn first(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
-        Nil => Ok(Nil),
+    if let Value::Lazy(_) = a[0].strip() {
+        return Ok(a[0].uncons()?.map_or(Null, |(first, _)| first));
+    }
+    match (a[0].strip(), a[0].seq()) {
+        (_, Some(seq)) => Ok(seq.into_iter().next().unwrap_or(Null)),
+        (Null, _) => Ok(Null),
         _ => error("invalid args to first"),
     }
 }
 
 fn rest(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
-        }
-        Nil => Ok(list![]),
+    if let Value::Lazy(_) = a[0].strip() {
+        return Ok(a[0].uncons()?.map_or(list(vec![]), |(_, rest)| rest));
+    }
+    match (a[0].strip(), a[0].seq()) {
+        (_, Some(seq)) => Ok(list(seq.into_iter().skip(1).collect())),
+        (Null, _) => Ok(list(vec![])),
         _ => error("invalid args to first"),
     }
 }
 
 fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
+    realize(&a[a.len() - 1])?;
+    match a[a.len() - 1].seq() {
+        Some(v) => {
+            check_size(a.len() - 2 + v.len())?;
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(v);
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
+        None => error("apply called with non-seq"),
     }
 }
 
 fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
+    realize(&a[1])?;
+    match a[1].seq() {
+        Some(v) => {
+            check_size(v.len())?;
             let mut res = vec![];
-            for mv in v.iter() {
-                res.push(a[0].apply(vec![mv.clone()])?)
+            for mv in v.into_iter() {
+                res.push(a[0].apply(vec![mv])?)
             }
-            Ok(list!(res))
+            Ok(list(res))
         }
-        _ => error("map called with non-seq"),
+        None => error("map called with non-seq"),
     }
 }
 
 fn conj(a: MalArgs) -> MalRet {
-    match a[0] {
-        List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+    realize(&a[0])?;
+    if let Some(v) = a[0].seq() {
+        check_size(v.len() + a.len() - 1)?;
+    }
+    match a[0].strip() {
+        List(ref v) => {
+            let mut l = v.clone();
+            for x in a[1..].iter() {
+                l.push_front(x.clone());
+            }
+            Ok(List(l))
+        }
+        Value::Vec(ref v) => Ok(vector([&v[..], &a[1..]].concat())),
+        // Like a list, the new elements go in front
+        Value::Lazy(_) => {
+            let mut l: Vec<Value> = a[1..].iter().rev().cloned().collect();
+            l.extend(a[0].seq().unwrap_or_default());
+            Ok(list(l))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
         _ => error("conj: called with non-seq"),
     }
 }
 
 fn seq(a: MalArgs) -> MalRet {
+    // A lazy seq stays lazy past its first element
+    if let Value::Lazy(_) = a[0].strip() {
+        return Ok(match a[0].uncons()? {
+            Some(_) => a[0].clone(),
+            None => Null,
+        });
+    }
+    match (a[0].strip(), a[0].seq()) {
+        (_, Some(v)) if v.is_empty() => Ok(Null),
+        (_, Some(v)) => Ok(list(v)),
+        (Value::String(ref s), _) if s.is_empty() => Ok(Null),
+        (Value::String(ref s), _) if !a[0].keyword_q() => Ok(list(s.chars().map(Char).collect())),
+        (Null, _) => Ok(Null),
+        _ => error("seq: called with non-seq"),
+    }
+}
+
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Returns a symbol name that was never returned before
+pub fn gensym(prefix: &str) -> String {
+    format!(
+        "{}{}",
+        prefix,
+        GENSYM_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// Returns the value try* hands to catch* clauses. Errors from native
+// functions become maps like those of ex-info, with :type :error.
+pub fn error_value(e: &MalErr) -> Value {
+    match e {
+        ErrMalVal(mv) => mv.clone(),
+        ErrString(s) => hash_map(vec![
+            keyword("type"),
+            keyword("error"),
+            keyword("message"),
+            Value::String(s.to_string()),
+        ])
+        .unwrap_or(Null),
+        ErrLimit(what) => hash_map(vec![
+            keyword("type"),
+            keyword("limit"),
+            keyword("message"),
+            Value::String(what.to_string()),
+        ])
+        .unwrap_or(Null),
+    }
+}
+
+// (ex-info msg data) is a map with :message and :data, and the :type of the
+// data, or :ex-info, so that catch* clauses can match on it
+fn ex_info(a: MalArgs) -> MalRet {
+    let data = a.get(1).cloned().unwrap_or(Null);
+    let kind = data.field("type").cloned();
+    match a.first() {
+        Some(msg @ Value::String(_)) => hash_map(vec![
+            keyword("type"),
+            kind.unwrap_or_else(|| keyword("ex-info")),
+            keyword("message"),
+            msg.clone(),
+            keyword("data"),
+            data,
+        ]),
+        _ => error("ex-info: expected a message string"),
+    }
+}
+
+fn ex_field(a: &MalArgs, key: &str) -> MalRet {
+    match a.first() {
+        Some(v) => Ok(v.field(key).cloned().unwrap_or(Null)),
+        _ => Ok(Null),
+    }
+}
+
+fn ex_message(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Value::String(_)) if !a[0].keyword_q() => Ok(a[0].clone()),
+        _ => ex_field(&a, "message"),
+    }
+}
+
+// Vars are named by their qualified symbol, see the var special form
+fn meta(a: MalArgs) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
+        Symbol(ref s) => Ok(var_meta(s).unwrap_or(Null)),
+        _ => a[0].get_meta(),
+    }
+}
+
+// Returns the qualified symbols of the vars whose name contains the string
+fn apropos(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Value::String(ref s)) | Some(Symbol(ref s)) => Ok(list(
+            all_vars()
+                .into_iter()
+                .filter(|v| v.rsplit('/').next().is_some_and(|n| n.contains(s.as_str())))
+                .map(Symbol)
+                .collect(),
+        )),
+        _ => error("apropos: expected a string"),
+    }
+}
+
+// Multimethods
+
+struct MultiFn {
+    dispatch: Value,
+    default: Value,
+    // Dispatch value and method, in the order they were added
+    methods: Vec<(Value, Value)>,
+    // (x, y) when x is preferred over y
+    prefers: Vec<(Value, Value)>,
+}
+
+global! {
+    // The multimethods by their qualified name
+    static MULTIMETHODS: Lock<FnvHashMap<String, MultiFn>> = Lock::new(FnvHashMap::default());
+    // The global hierarchy as (child, parent) pairs, see derive
+    static HIERARCHY: Lock<Vec<(Value, Value)>> = Lock::new(vec![]);
+}
+
+// Registers a multimethod unless there is one by that name already, so that
+// loading a file again keeps the methods
+pub fn defmulti(name: &str, dispatch: Value, default: Value) {
+    MULTIMETHODS.with(|mm| {
+        mm.borrow_mut().entry(name.to_string()).or_insert(MultiFn {
+            dispatch,
+            default,
+            methods: vec![],
+            prefers: vec![],
+        });
+    });
+}
+
+pub fn defmethod(name: &str, dispatch_val: Value, method: Value) -> MalRet {
+    with_multifn(name, |m| {
+        m.methods.retain(|(k, _)| !k.equals(&dispatch_val));
+        m.methods.push((dispatch_val, method));
+        Ok(Null)
+    })
+}
+
+fn with_multifn<F>(name: &str, f: F) -> MalRet
+where
+    F: FnOnce(&mut MultiFn) -> MalRet,
+{
+    MULTIMETHODS.with(|mm| match mm.borrow_mut().get_mut(name) {
+        Some(m) => f(m),
+        None => error(&format!("no multimethod '{}'", name)),
+    })
+}
+
+// Returns the name of the multimethod a value made by defmulti is for
+fn multifn_name(mf: &Value) -> Result<String, MalErr> {
+    match mf.get_meta()?.field("multi") {
+        Some(Symbol(ref name)) => Ok(name.to_string()),
+        _ => Err(ErrString("expected a multimethod".to_string())),
+    }
+}
+
+fn isa(child: &Value, parent: &Value) -> bool {
+    if child.equals(parent) {
+        return true;
+    }
+    if let (Value::Vec(c), Value::Vec(p)) = (child.strip(), parent.strip()) {
+        if c.len() == p.len() {
+            return c.iter().zip(p.iter()).all(|(c, p)| isa(c, p));
         }
-        Nil => Ok(Nil),
-        _ => error("seq: called with non-seq"),
     }
+    let parents: Vec<Value> = HIERARCHY.with(|h| {
+        h.borrow()
+            .iter()
+            .filter(|(c, _)| c.equals(child))
+            .map(|(_, p)| p.clone())
+            .collect()
+    });
+    parents.iter().any(|p| isa(p, parent))
 }
 
-pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
-        (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
-        ),
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        ("number?", func(fn_is_type!(Int(_)))),
-        (
-            "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
-        ),
-        (
-            "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
-        ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
-        (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
-        ),
-        (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
-        ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+fn derive(a: MalArgs) -> MalRet {
+    if isa(&a[1], &a[0]) {
+        return error(&format!(
+            "derive: {} is already an ancestor of {}",
+            a[0].pr_str(true),
+            a[1].pr_str(true)
+        ));
+    }
+    HIERARCHY.with(|h| h.borrow_mut().push((a[0].clone(), a[1].clone())));
+    Ok(Null)
+}
+
+// Finds the method for the dispatch value: the one for that exact value,
+// else the most specific one whose value it isa?, else the default
+fn find_method(m: &MultiFn, dv: &Value) -> MalRet {
+    if let Some((_, f)) = m.methods.iter().find(|(k, _)| k.equals(dv)) {
+        return Ok(f.clone());
+    }
+    // Preferred or more specific
+    let dominates = |x: &Value, y: &Value| {
+        !x.equals(y) && (m.prefers.iter().any(|(p, q)| p.equals(x) && q.equals(y)) || isa(x, y))
+    };
+    let matching: Vec<&(Value, Value)> = m.methods.iter().filter(|(k, _)| isa(dv, k)).collect();
+    let best: Vec<&&(Value, Value)> = matching
+        .iter()
+        .filter(|(k, _)| !matching.iter().any(|(other, _)| dominates(other, k)))
+        .collect();
+    match best.len() {
+        0 => match m.methods.iter().find(|(k, _)| k.equals(&m.default)) {
+            Some((_, f)) => Ok(f.clone()),
+            None => error(&format!("no method for dispatch value {}", dv.pr_str(true))),
+        },
+        1 => Ok(best[0].1.clone()),
+        _ => error(&format!(
+            "multiple methods match dispatch value {}: {} and {}, and neither is preferred",
+            dv.pr_str(true),
+            best[0].0.pr_str(true),
+            best[1].0.pr_str(true)
+        )),
+    }
+}
+
+// (multifn-dispatch name args) calls the method of the multimethod for args.
+// The functions made by defmulti call it.
+fn multifn_dispatch(a: MalArgs) -> MalRet {
+    let (name, args) = match (&a[0], a[1].seq()) {
+        (Symbol(ref name), Some(args)) => (name.to_string(), args),
+        _ => return error("multifn-dispatch: expected a name and arguments"),
+    };
+    // Nothing is borrowed while the dispatch function and method run, they
+    // may define methods themselves
+    let dispatch = with_multifn(&name, |m| Ok(m.dispatch.clone()))?;
+    let dv = dispatch.apply(args.clone())?;
+    let method = with_multifn(&name, |m| find_method(m, &dv))?;
+    method.apply(args)
+}
+
+fn remove_method(a: MalArgs) -> MalRet {
+    with_multifn(&multifn_name(&a[0])?, |m| {
+        m.methods.retain(|(k, _)| !k.equals(&a[1]));
+        Ok(Null)
+    })
+}
+
+fn prefer_method(a: MalArgs) -> MalRet {
+    with_multifn(&multifn_name(&a[0])?, |m| {
+        if m.prefers
+            .iter()
+            .any(|(p, q)| p.equals(&a[2]) && q.equals(&a[1]))
+        {
+            return error(&format!(
+                "prefer-method: {} is already preferred over {}",
+                a[2].pr_str(true),
+                a[1].pr_str(true)
+            ));
+        }
+        m.prefers.push((a[1].clone(), a[2].clone()));
+        Ok(Null)
+    })
+}
+
+// Returns the [dispatch-value method] pairs of the multimethod. Dispatch
+// values can be any value, so this is not a map.
+fn methods(a: MalArgs) -> MalRet {
+    with_multifn(&multifn_name(&a[0])?, |m| {
+        Ok(list(
+            m.methods
+                .iter()
+                .map(|(k, f)| vector(vec![k.clone(), f.clone()]))
+                .collect(),
+        ))
+    })
+}
+
+// Protocols and records
+
+// The type names extend-type takes besides records, Object extends all types
+pub const BUILTIN_TYPES: &[&str] = &[
+    "nil", "Boolean", "Number", "String", "Keyword", "Symbol", "List", "Vector", "Map", "Function",
+    "Atom", "Ref", "Char", "LazySeq", "Channel", "Object",
+];
+
+global! {
+    // The qualified names of the methods of each protocol
+    static PROTOCOLS: Lock<FnvHashMap<String, Vec<String>>> = Lock::new(FnvHashMap::default());
+    // The implementation of each method for each type
+    static PROTOCOL_IMPLS: Lock<FnvHashMap<(String, String), Value>> =
+        Lock::new(FnvHashMap::default());
+}
+
+// Returns the name of the type of the value. Records carry it in their
+// metadata as :type.
+pub fn type_name(v: &Value) -> String {
+    match v {
+        Null => "nil",
+        Boolean(_) => "Boolean",
+        Number(_) => "Number",
+        Value::String(_) if v.keyword_q() => "Keyword",
+        Value::String(_) | Value::Error(_) => "String",
+        Symbol(_) | Value::Keyword(_) => "Symbol",
+        List(_) => "List",
+        Value::Vec(_) => "Vector",
+        Map(_) => "Map",
+        Value::Meta(ref v, ref meta) => match (&**v, meta.field("type")) {
+            (Map(_), Some(Symbol(ref t))) => return t.to_string(),
+            _ => return type_name(v),
+        },
+        Value::Func(_) | Value::Closure(_) | Value::Cont(_) => "Function",
+        Value::Atom(_) => "Atom",
+        Value::Ref(_) => "Ref",
+        Value::Lazy(_) => "LazySeq",
+        Value::Chan(_) => "Channel",
+        #[cfg(feature = "threads")]
+        Value::Future(_) => "Future",
+        Char(_) => "Char",
+    }
+    .to_string()
+}
+
+pub fn defprotocol(name: &str, methods: Vec<String>) {
+    PROTOCOLS.with(|p| p.borrow_mut().insert(name.to_string(), methods));
+}
+
+// Returns the qualified names of the methods of the protocol
+pub fn protocol_methods(name: &str) -> Option<Vec<String>> {
+    PROTOCOLS.with(|p| p.borrow().get(name).cloned())
+}
+
+pub fn extend_protocol(method: &str, type_name: &str, f: Value) {
+    PROTOCOL_IMPLS.with(|p| {
+        p.borrow_mut()
+            .insert((method.to_string(), type_name.to_string()), f)
+    });
+}
+
+// Returns the implementation of the method for the type of the first
+// argument, or the one for Object
+pub fn protocol_impl(method: &str, args: &MalArgs) -> MalRet {
+    let t = match args.first() {
+        Some(this) => type_name(this),
+        None => return error(&format!("{} needs at least one argument", method)),
+    };
+    PROTOCOL_IMPLS.with(|p| {
+        let p = p.borrow();
+        match p
+            .get(&(method.to_string(), t.clone()))
+            .or_else(|| p.get(&(method.to_string(), "Object".to_string())))
+        {
+            Some(f) => Ok(f.clone()),
+            None => error(&format!("no implementation of {} for type {}", method, t)),
+        }
+    })
+}
+
+// Returns the method a function made by defprotocol stands for
+pub fn protocol_method(meta: &Value) -> Option<String> {
+    match meta.field("protocol-method") {
+        Some(Symbol(ref m)) => Some(m.to_string()),
+        _ => None,
+    }
+}
+
+// (protocol-dispatch method args) calls the implementation of the method for
+// args. The evaluator calls implementations directly, this is for apply and
+// the other native functions calling the functions made by defprotocol.
+fn protocol_dispatch(a: MalArgs) -> MalRet {
+    match (&a[0], a[1].seq()) {
+        (Symbol(ref method), Some(args)) => protocol_impl(method, &args)?.apply(args),
+        _ => error("protocol-dispatch: expected a method and arguments"),
+    }
+}
+
+fn satisfies(a: MalArgs) -> MalRet {
+    let methods = match a[0] {
+        Symbol(ref p) => protocol_methods(p).unwrap_or_default(),
+        _ => return error("satisfies?: expected a protocol"),
+    };
+    let args = vec![a[1].clone()];
+    Ok(Boolean(
+        methods.iter().any(|m| protocol_impl(m, &args).is_ok()),
+    ))
+}
+
+// (make-record type map) makes a record of the type with the fields of the
+// map. Records are maps, so fields are read with get, but assoc and dissoc
+// return plain maps.
+fn make_record(a: MalArgs) -> MalRet {
+    match (&a[0], a[1].strip()) {
+        (Symbol(_), Map(_)) => a[1].with_meta(&hash_map(vec![keyword("type"), a[0].clone()])?),
+        _ => error("make-record: expected a type and a map"),
+    }
+}
+
+// Conditions and restarts
+
+thread_local! {
+    // The handlers of the enclosing handler-binds, innermost last, as the
+    // condition type or predicate, the handler, and where its handler-bind
+    // starts in the stack
+    static HANDLERS: RefCell<Vec<(Value, Value, usize)>> = const { RefCell::new(vec![]) };
+    // The restarts of the enclosing restart-cases, innermost last, as name and id
+    static RESTARTS: RefCell<Vec<(String, usize)>> = const { RefCell::new(vec![]) };
+}
+
+static RESTART_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Establishes the handlers of a handler-bind, until pop_handlers removes them
+pub fn push_handlers(handlers: Vec<(Value, Value)>) {
+    HANDLERS.with(|h| {
+        let mut h = h.borrow_mut();
+        let len = h.len();
+        h.extend(handlers.into_iter().map(|(m, f)| (m, f, len)));
+    });
+}
+
+pub fn pop_handlers(n: usize) {
+    HANDLERS.with(|h| {
+        let mut h = h.borrow_mut();
+        let len = h.len();
+        h.truncate(len - n);
+    });
+}
+
+fn condition_matches(matcher: &Value, cond: &Value) -> Result<bool, MalErr> {
+    Ok(match matcher {
+        Boolean(b) => *b,
+        Value::String(_) if matcher.keyword_q() => cond.field("type") == Some(matcher),
+        _ => matcher.apply(vec![cond.clone()])?.truthy(),
+    })
+}
+
+// Calls the handlers for the condition, innermost first, without unwinding.
+// A handler declines by returning, and handles the condition by invoking a
+// restart. While it runs, only the handlers outside its handler-bind are
+// established.
+pub fn signal(cond: &Value) -> MalRet {
+    let handlers = HANDLERS.with(|h| h.borrow().clone());
+    for (matcher, handler, start) in handlers.iter().rev() {
+        if !condition_matches(matcher, cond)? {
+            continue;
+        }
+        HANDLERS.with(|h| h.borrow_mut().truncate(*start));
+        let res = handler.apply(vec![cond.clone()]);
+        HANDLERS.with(|h| *h.borrow_mut() = handlers.clone());
+        res?;
+    }
+    Ok(Null)
+}
+
+// Returns the first of n new restart ids
+pub fn restart_ids(n: usize) -> usize {
+    RESTART_COUNTER.fetch_add(n, Ordering::Relaxed)
+}
+
+// Establishes the named restarts, with the ids from `first` on, until
+// pop_restarts removes them
+pub fn push_restarts(names: &[String], first: usize) {
+    RESTARTS.with(|r| {
+        r.borrow_mut().extend(
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (n.to_string(), first + i)),
+        )
+    });
+}
+
+pub fn pop_restarts(n: usize) {
+    RESTARTS.with(|r| {
+        let mut r = r.borrow_mut();
+        let len = r.len();
+        r.truncate(len - n);
+    });
+}
+
+// Returns the restart id and arguments if the error is a restart being
+// invoked, which try* lets through
+pub fn restart_transfer(e: &MalErr) -> Option<(usize, MalArgs)> {
+    match e {
+        ErrMalVal(v) => match (v.field("mal/restart"), v.field("args")) {
+            (Some(id), Some(args)) => Some((int_value(id)? as usize, args.seq()?)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn invoke_restart(a: MalArgs) -> MalRet {
+    let name = match a.first() {
+        Some(Symbol(ref s)) | Some(Value::String(ref s)) => {
+            s.trim_start_matches('\u{29e}').to_string()
+        }
+        _ => return error("invoke-restart: expected a restart name"),
+    };
+    let id = RESTARTS.with(|r| {
+        r.borrow()
+            .iter()
+            .rev()
+            .find(|(n, _)| n == &name)
+            .map(|(_, id)| *id)
+    });
+    match id {
+        Some(id) => Err(ErrMalVal(hash_map(vec![
+            keyword("mal/restart"),
+            Number(id as f64),
+            keyword("args"),
+            list(a[1..].to_vec()),
+        ])?)),
+        None => error(&format!("no restart named {} is active", name)),
+    }
+}
+
+// Signals an error of a native function to the handlers, offering a
+// use-value restart that makes the call return the value instead
+pub fn native_error(err: MalErr) -> MalRet {
+    if HANDLERS.with(|h| h.borrow().is_empty()) {
+        return Err(err);
+    }
+    let cond = error_value(&err);
+    let id = restart_ids(1);
+    push_restarts(&["use-value".to_string()], id);
+    let res = signal(&cond);
+    pop_restarts(1);
+    match res {
+        Ok(_) => Err(err),
+        Err(ref e) => match restart_transfer(e) {
+            Some((invoked, args)) if invoked == id => Ok(args.first().cloned().unwrap_or(Null)),
+            _ => res,
+        },
+    }
+}
+
+// Channels and go blocks
+//
+// A go block is a reset run by the scheduler below, on this thread. A
+// channel operation captures the rest of its block with shift and parks it
+// on the channel, or queues it right away when the operation can complete.
+
+type Ready = (Value, MalArgs, Value);
+
+thread_local! {
+    // Continuations ready to run, with their argument and result channel
+    static RUN_QUEUE: RefCell<VecDeque<Ready>> = const { RefCell::new(VecDeque::new()) };
+    // The channels made by timeout, closed at their deadline
+    static TIMERS: RefCell<Vec<(Instant, Value)>> = const { RefCell::new(vec![]) };
+    // The result channel of the go block being run, nil outside go blocks
+    static CURRENT_GO: RefCell<Value> = const { RefCell::new(Null) };
+    // Set by a channel operation that parked the go block it was called from
+    static PARKED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn new_chan(cap: usize) -> Value {
+    Value::Chan(Shared::new(Channel::new(cap)))
+}
+
+fn chan_of(v: &Value) -> Result<Shared<Channel>, MalErr> {
+    match v {
+        Value::Chan(ch) => Ok(ch.clone()),
+        _ => error("expected a channel"),
+    }
+}
+
+fn current_go() -> Value {
+    CURRENT_GO.with(|c| c.borrow().clone())
+}
+
+fn schedule(k: Value, args: MalArgs, result: Value) {
+    RUN_QUEUE.with(|q| q.borrow_mut().push_back((k, args, result)));
+}
+
+// Ends the go block the channel operation was called from, which the
+// scheduler tells from one that returned
+fn park() -> MalRet {
+    PARKED.with(|p| p.set(true));
+    Ok(Null)
+}
+
+// Queues the waiter with the value, unless it is an alts! that already
+// completed another operation
+fn wake(w: Waiter, v: Value) -> bool {
+    let arg = match w.alt {
+        Some(ref done) if *done.borrow() => return false,
+        Some(ref done) => {
+            *done.borrow_mut() = true;
+            vector(vec![v, w.port.clone()])
+        }
+        None => v,
+    };
+    schedule(w.k, vec![arg], w.result);
+    true
+}
+
+// Takes a value if one is there, nil once the channel is closed and empty,
+// or None when the taker has to wait
+fn try_take(ch: &Channel) -> Option<Value> {
+    let mut ch = ch.0.borrow_mut();
+    if let Some(v) = ch.buf.pop_front() {
+        // There is room in the buffer for a waiting putter now
+        while let Some((pv, w)) = ch.putters.pop_front() {
+            if wake(w, Boolean(true)) {
+                ch.buf.push_back(pv);
+                break;
+            }
+        }
+        return Some(v);
+    }
+    while let Some((pv, w)) = ch.putters.pop_front() {
+        if wake(w, Boolean(true)) {
+            return Some(pv);
+        }
+    }
+    if ch.closed {
+        Some(Null)
+    } else {
+        None
+    }
+}
+
+// Puts the value if a taker or room in the buffer is there, giving false
+// once the channel is closed, or None when the putter has to wait
+fn try_put(ch: &Channel, v: &Value) -> Option<bool> {
+    let mut ch = ch.0.borrow_mut();
+    if ch.closed {
+        return Some(false);
+    }
+    while let Some(w) = ch.takers.pop_front() {
+        if wake(w, v.clone()) {
+            return Some(true);
+        }
+    }
+    if ch.buf.len() < ch.cap {
+        ch.buf.push_back(v.clone());
+        return Some(true);
+    }
+    None
+}
+
+fn close(ch: &Channel) {
+    let mut ch = ch.0.borrow_mut();
+    ch.closed = true;
+    while let Some(w) = ch.takers.pop_front() {
+        wake(w, Null);
+    }
+}
+
+fn waiter(k: &Value, alt: Option<Shared<Lock<bool>>>, port: Value) -> Waiter {
+    Waiter {
+        k: k.clone(),
+        result: current_go(),
+        alt,
+        port,
+    }
+}
+
+// (check-go op) is called by the channel operations that park their go
+// block before they shift, so that they report being used outside of one
+fn check_go(a: MalArgs) -> MalRet {
+    match current_go() {
+        Null => error(&format!("{}: not in a go block", a[0].pr_str(false))),
+        _ => Ok(Null),
+    }
+}
+
+// (chan-take ch k) is the handler of the shift in <!
+fn chan_take(a: MalArgs) -> MalRet {
+    let ch = chan_of(&a[0])?;
+    match try_take(&ch) {
+        Some(v) => schedule(a[1].clone(), vec![v], current_go()),
+        None => {
+            ch.0.borrow_mut()
+                .takers
+                .push_back(waiter(&a[1], None, Null))
+        }
+    }
+    park()
+}
+
+// (chan-put ch v k) is the handler of the shift in >!
+fn chan_put(a: MalArgs) -> MalRet {
+    let ch = chan_of(&a[0])?;
+    match try_put(&ch, &a[1]) {
+        Some(ok) => schedule(a[2].clone(), vec![Boolean(ok)], current_go()),
+        None => {
+            let w = waiter(&a[2], None, Null);
+            ch.0.borrow_mut().putters.push_back((a[1].clone(), w))
+        }
+    }
+    park()
+}
+
+// Returns the channel and value of an alts! put, [channel value]
+fn alt_put(op: &Value) -> Option<(Value, Value)> {
+    match op.strip() {
+        Value::Vec(ref put) if put.len() == 2 => Some((put[0].clone(), put[1].clone())),
+        _ => None,
+    }
+}
+
+// (chan-alts ops k) is the handler of the shift in alts!. Each op is a
+// channel to take from or [channel value] to put, and the first that can
+// complete, in order, gives [value channel].
+fn chan_alts(a: MalArgs) -> MalRet {
+    let ops = match a[0].seq() {
+        Some(ops) if !ops.is_empty() => ops,
+        _ => return error("alts!: expected a vector of operations"),
+    };
+    let k = &a[1];
+    for op in ops.iter() {
+        let done = match alt_put(op) {
+            Some((port, v)) => try_put(&*chan_of(&port)?, &v).map(|ok| (Boolean(ok), port)),
+            None => try_take(&*chan_of(op)?).map(|v| (v, op.clone())),
+        };
+        if let Some((v, port)) = done {
+            schedule(k.clone(), vec![vector(vec![v, port])], current_go());
+            return park();
+        }
+    }
+    let done = Shared::new(Lock::new(false));
+    for op in ops.iter() {
+        match alt_put(op) {
+            Some((port, v)) => {
+                let w = waiter(k, Some(done.clone()), port.clone());
+                chan_of(&port)?.0.borrow_mut().putters.push_back((v, w))
+            }
+            None => {
+                let w = waiter(k, Some(done.clone()), op.clone());
+                chan_of(op)?.0.borrow_mut().takers.push_back(w)
+            }
+        }
+    }
+    park()
+}
+
+fn timeout(a: MalArgs) -> MalRet {
+    let ms = match int_value(&a[0]) {
+        Some(ms) if ms >= 0 => ms as u64,
+        _ => return error("timeout: expected milliseconds"),
+    };
+    let ch = new_chan(0);
+    let deadline = Instant::now() + Duration::from_millis(ms);
+    TIMERS.with(|t| t.borrow_mut().push((deadline, ch.clone())));
+    Ok(ch)
+}
+
+// (go* f) runs f, a function of no arguments wrapping its body in reset, as
+// a go block, and returns the channel its result goes to
+fn go(a: MalArgs) -> MalRet {
+    let result = new_chan(1);
+    schedule(a[0].clone(), vec![], result.clone());
+    Ok(result)
+}
+
+// Runs one ready go block, or closes the next timeout channel, sleeping
+// until its deadline if `wait`. Returns false when there is nothing to do.
+// What a go block throws closes its channel and is thrown from here.
+fn step(wait: bool) -> Result<bool, MalErr> {
+    if let Some((f, args, result)) = RUN_QUEUE.with(|q| q.borrow_mut().pop_front()) {
+        let outer = CURRENT_GO.with(|c| c.replace(result.clone()));
+        PARKED.with(|p| p.set(false));
+        let res = f.apply(args);
+        let parked = PARKED.with(|p| p.replace(false));
+        CURRENT_GO.with(|c| *c.borrow_mut() = outer);
+        let ch = chan_of(&result)?;
+        match res {
+            Ok(_) if parked => (),
+            Ok(v) => {
+                if v != Null {
+                    try_put(&ch, &v);
+                }
+                close(&ch);
+            }
+            Err(e) => {
+                close(&ch);
+                return Err(e);
+            }
+        }
+        return Ok(true);
+    }
+    let next = TIMERS.with(|t| {
+        let t = t.borrow();
+        t.iter()
+            .enumerate()
+            .min_by_key(|(_, (d, _))| *d)
+            .map(|(i, (d, _))| (i, *d))
+    });
+    match next {
+        Some((i, deadline)) if wait || deadline <= Instant::now() => {
+            let now = Instant::now();
+            if deadline > now {
+                sleep(deadline - now);
+            }
+            let (_, ch) = TIMERS.with(|t| t.borrow_mut().remove(i));
+            close(&*chan_of(&ch)?);
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+// Runs go blocks until none is ready, and when `wait` also until all the
+// timeouts have passed
+pub fn run_scheduler(wait: bool) -> Result<(), MalErr> {
+    while step(wait)? {}
+    Ok(())
+}
+
+// (<!! ch) takes from a channel outside of go blocks, running the go blocks
+// until a value is there
+fn blocking_take(a: MalArgs) -> MalRet {
+    let ch = chan_of(&a[0])?;
+    loop {
+        if let Some(v) = try_take(&ch) {
+            return Ok(v);
+        }
+        if !step(true)? {
+            return error("<!!: deadlock, no go block can put to the channel");
+        }
+    }
+}
+
+// Atom watches and validators
+//
+// An atom holds its watches and validator next to its value. Every change
+// goes through set_atom.
+
+fn atom_cell(a: &Value) -> Result<&AtomCell, MalErr> {
+    match a {
+        Value::Atom(Atom(ref cell)) => Ok(cell),
+        _ => error("expected an atom"),
+    }
+}
+
+// Returns what holds the watches and validator of an atom or ref, which for
+// a ref is the atom holding it
+fn watched(a: &Value) -> Value {
+    ref_cell(a).unwrap_or_else(|| a.clone())
+}
+
+fn atom_key(a: &Value) -> Result<usize, MalErr> {
+    Ok(atom_cell(a)? as *const AtomCell as usize)
+}
+
+fn validate(cell: &AtomCell, v: &Value) -> Result<(), MalErr> {
+    let f = cell.validator.borrow().clone();
+    if f != Null && !f.apply(vec![v.clone()])?.truthy() {
+        return error("invalid reference state");
+    }
+    Ok(())
+}
+
+// Sets the atom to `new` once its validator accepts it, as long as it still
+// holds `expected` when given, calls its watches with the old and new value
+// and returns the old one, or None when it held something else
+fn set_atom_if(a: &Value, expected: Option<&Value>, new: Value) -> Result<Option<Value>, MalErr> {
+    let cell = atom_cell(a)?;
+    validate(cell, &new)?;
+    let old = {
+        let mut value = cell.borrow_mut();
+        if expected.is_some_and(|e| *e != *value) {
+            return Ok(None);
+        }
+        std::mem::replace(&mut *value, new.clone())
+    };
+    let watches = cell.watches.borrow().clone();
+    for (k, f) in watches {
+        f.apply(vec![k, a.clone(), old.clone(), new.clone()])?;
+    }
+    Ok(Some(old))
+}
+
+fn set_atom(a: &Value, new: Value) -> MalRet {
+    Ok(set_atom_if(a, None, new)?.unwrap_or(Null))
+}
+
+// Returns the old and the new value of (swap! a f args...). When another
+// thread changed the atom while f ran, f runs again on its new value.
+fn swap(a: &MalArgs) -> Result<(Value, Value), MalErr> {
+    loop {
+        let old = a[0].deref()?;
+        let mut args = vec![old.clone()];
+        args.extend_from_slice(&a[2..]);
+        let new = a[1].apply(args)?;
+        if set_atom_if(&a[0], Some(&old), new.clone())?.is_some() {
+            return Ok((old, new));
+        }
+    }
+}
+
+fn compare_and_set(a: MalArgs) -> MalRet {
+    Ok(Boolean(
+        set_atom_if(&a[0], Some(&a[1]), a[2].clone())?.is_some(),
+    ))
+}
+
+fn add_watch(a: MalArgs) -> MalRet {
+    let target = watched(&a[0]);
+    let mut watches = atom_cell(&target)?.watches.borrow_mut();
+    // Adding a watch again under the same key replaces it
+    watches.retain(|(k, _)| k != &a[1]);
+    watches.push((a[1].clone(), a[2].clone()));
+    Ok(a[0].clone())
+}
+
+fn remove_watch(a: MalArgs) -> MalRet {
+    atom_cell(&watched(&a[0]))?
+        .watches
+        .borrow_mut()
+        .retain(|(k, _)| k != &a[1]);
+    Ok(a[0].clone())
+}
+
+// (set-validator! a f) checks the current value with f before keeping it,
+// nil removes the validator
+fn set_validator(a: MalArgs) -> MalRet {
+    let target = watched(&a[0]);
+    let cell = atom_cell(&target)?;
+    if a[1] != Null && !a[1].apply(vec![deref(vec![a[0].clone()])?])?.truthy() {
+        return error("invalid reference state");
+    }
+    *cell.validator.borrow_mut() = a[1].clone();
+    Ok(Null)
+}
+
+fn get_validator(a: MalArgs) -> MalRet {
+    Ok(atom_cell(&watched(&a[0]))?.validator.borrow().clone())
+}
+
+// Refs and transactions
+//
+// A ref holds an atom with its version and value, [version value], which
+// only the functions below get at. A transaction reads every ref as of the version of the last commit when it
+// started, and runs again when it meets a ref committed since. It keeps its
+// own values for the refs it touched and commits them together, checking
+// under COMMIT_LOCK that the refs it wrote or ensured are still at the
+// versions it saw, and runs again otherwise. With the threads feature the
+// atoms are shared, so transactions on different threads are coordinated
+// too. Nothing is written before the commit, and the commit checks every new
+// value before it writes any, so a throw out of dosync or a validator leaves
+// the refs as they were.
+
+static COMMIT_LOCK: Mutex<()> = Mutex::new(());
+// The version of the last commit, which writes its refs at that version
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+const MAX_RETRIES: usize = 10000;
+
+struct TxRef {
+    cell: Value,
+    version: usize,
+    value: Value,
+    written: bool,
+    ensured: bool,
+}
+
+type Commute = (Value, Value, Vec<(Value, MalArgs)>);
+
+struct Tx {
+    // The version of the last commit when the transaction started
+    read_point: usize,
+    // Set once the transaction met a ref committed after its read point, it
+    // then runs again whatever it returns
+    doomed: bool,
+    refs: FnvHashMap<usize, TxRef>,
+    // Refs only commuted, with their value in the transaction and the
+    // functions to apply again to the committed value
+    commutes: FnvHashMap<usize, Commute>,
+}
+
+thread_local! {
+    static TX: RefCell<Option<Tx>> = const { RefCell::new(None) };
+}
+
+// Returns the atom holding the ref's version and value
+fn ref_cell(v: &Value) -> Option<Value> {
+    match v {
+        Value::Ref(a) => Some(Value::Atom(a.clone())),
+        _ => None,
+    }
+}
+
+fn committed(cell: &Value) -> Result<(usize, Value), MalErr> {
+    match cell.deref()? {
+        Value::Vec(ref v) if v.len() == 2 => match int_value(&v[0]) {
+            Some(version) => Ok((version as usize, v[1].clone())),
+            _ => error("invalid ref state"),
+        },
+        _ => error("invalid ref state"),
+    }
+}
+
+fn in_tx() -> bool {
+    TX.with(|tx| tx.borrow().is_some())
+}
+
+// Gives the ref's cell and key, erroring outside of a transaction
+fn tx_ref(v: &Value, name: &str) -> Result<(Value, usize), MalErr> {
+    let cell = match ref_cell(v) {
+        Some(cell) => cell,
+        None => return error(&format!("{}: expected a ref", name)),
+    };
+    if !in_tx() {
+        return error(&format!("{}: no transaction running", name));
+    }
+    let key = atom_key(&cell)?;
+    Ok((cell, key))
+}
+
+// Returns the committed version and value of the ref, as of the read point
+// of the transaction. A ref committed since dooms the transaction.
+fn snapshot(cell: &Value) -> Result<(usize, Value), MalErr> {
+    let (version, value) = committed(cell)?;
+    let doomed = TX.with(|tx| {
+        let mut tx = tx.borrow_mut();
+        let tx = tx.as_mut().unwrap();
+        tx.doomed |= version > tx.read_point;
+        tx.doomed
+    });
+    if doomed {
+        return error("dosync: a ref changed since the transaction started, it runs again");
+    }
+    Ok((version, value))
+}
+
+// Returns the ref's value in the transaction, recording the version seen
+// the first time the transaction touches it
+fn touch(cell: &Value, key: usize) -> MalRet {
+    let seen = TX.with(|tx| {
+        let tx = tx.borrow();
+        let tx = tx.as_ref().unwrap();
+        match tx.commutes.get(&key) {
+            Some((_, v, _)) => Some(v.clone()),
+            None => tx.refs.get(&key).map(|r| r.value.clone()),
+        }
+    });
+    if let Some(v) = seen {
+        return Ok(v);
+    }
+    let (version, value) = snapshot(cell)?;
+    TX.with(|tx| {
+        let mut tx = tx.borrow_mut();
+        tx.as_mut().unwrap().refs.insert(
+            key,
+            TxRef {
+                cell: cell.clone(),
+                version,
+                value: value.clone(),
+                written: false,
+                ensured: false,
+            },
+        )
+    });
+    Ok(value)
+}
+
+fn update(key: usize, f: impl FnOnce(&mut TxRef)) {
+    TX.with(|tx| {
+        if let Some(r) = tx.borrow_mut().as_mut().unwrap().refs.get_mut(&key) {
+            f(r)
+        }
+    })
+}
+
+fn ref_deref(cell: &Value) -> MalRet {
+    if in_tx() {
+        touch(cell, atom_key(cell)?)
+    } else {
+        Ok(committed(cell)?.1)
+    }
+}
+
+fn ref_set(a: MalArgs) -> MalRet {
+    let (cell, key) = tx_ref(&a[0], "ref-set")?;
+    if TX.with(|tx| tx.borrow().as_ref().unwrap().commutes.contains_key(&key)) {
+        return error("ref-set: cannot set a ref after commuting it");
+    }
+    touch(&cell, key)?;
+    let v = a[1].clone();
+    update(key, |r| {
+        r.value = v.clone();
+        r.written = true;
+    });
+    Ok(v)
+}
+
+fn alter(a: MalArgs) -> MalRet {
+    let (cell, key) = tx_ref(&a[0], "alter")?;
+    let mut args = vec![touch(&cell, key)?];
+    args.extend_from_slice(&a[2..]);
+    ref_set(vec![a[0].clone(), a[1].apply(args)?])
+}
+
+// Like alter, but the function is applied again to the committed value when
+// the transaction commits, so other transactions changing the ref in between
+// do not make this one run again
+fn commute(a: MalArgs) -> MalRet {
+    let (cell, key) = tx_ref(&a[0], "commute")?;
+    if TX.with(|tx| tx.borrow().as_ref().unwrap().refs.contains_key(&key)) {
+        return alter(a);
+    }
+    let current = match TX.with(|tx| {
+        tx.borrow()
+            .as_ref()
+            .unwrap()
+            .commutes
+            .get(&key)
+            .map(|c| c.1.clone())
+    }) {
+        Some(v) => v,
+        None => snapshot(&cell)?.1,
+    };
+    let mut args = vec![current];
+    args.extend_from_slice(&a[2..]);
+    let v = a[1].apply(args)?;
+    TX.with(|tx| {
+        let mut tx = tx.borrow_mut();
+        let c = tx
+            .as_mut()
+            .unwrap()
+            .commutes
+            .entry(key)
+            .or_insert((cell.clone(), Null, vec![]));
+        c.1 = v.clone();
+        c.2.push((a[1].clone(), a[2..].to_vec()));
+    });
+    Ok(v)
+}
+
+// Makes the transaction run again if the ref changes before it commits,
+// even when it does not write the ref
+fn ensure(a: MalArgs) -> MalRet {
+    let (cell, key) = tx_ref(&a[0], "ensure")?;
+    let v = touch(&cell, key)?;
+    update(key, |r| r.ensured = true);
+    Ok(v)
+}
+
+// Writes the transaction's values, or returns false when a ref it depends on
+// changed since it was read. The commuted values are computed before taking
+// COMMIT_LOCK, as the functions may block or start transactions of their
+// own, and again when another commit changed those refs in between. The
+// watches of the refs run once all of them are written.
+fn commit(tx: Tx) -> Result<bool, MalErr> {
+    loop {
+        let mut commuted = vec![];
+        for (cell, _, fs) in tx.commutes.values() {
+            let (version, mut value) = committed(cell)?;
+            for (f, args) in fs {
+                let mut args = args.clone();
+                args.insert(0, value);
+                value = f.apply(args)?;
+            }
+            commuted.push((cell.clone(), version, value));
+        }
+        let guard = COMMIT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut writes = vec![];
+        for r in tx.refs.values() {
+            if (r.written || r.ensured) && committed(&r.cell)?.0 != r.version {
+                return Ok(false);
+            }
+            if r.written {
+                writes.push((r.cell.clone(), r.value.clone()));
+            }
+        }
+        let mut stale = false;
+        for (cell, version, value) in commuted {
+            stale |= committed(&cell)?.0 != version;
+            writes.push((cell, value));
+        }
+        if stale {
+            continue;
+        }
+        return write(writes, guard);
+    }
+}
+
+// Validates all the new values, then writes them at the next version and
+// releases the lock before running the watches
+fn write(writes: Vec<(Value, Value)>, guard: MutexGuard<()>) -> Result<bool, MalErr> {
+    for (cell, value) in writes.iter() {
+        validate(atom_cell(cell)?, value)?;
+    }
+    let version = CLOCK.load(Ordering::SeqCst) + 1;
+    let mut changes = vec![];
+    for (cell, value) in writes {
+        let (_, old) = committed(&cell)?;
+        atom_cell(&cell)?.replace(vector(vec![Number(version as f64), value.clone()]));
+        changes.push((cell, old, value));
+    }
+    CLOCK.store(version, Ordering::SeqCst);
+    drop(guard);
+    for (cell, old, new) in changes {
+        let r = match cell {
+            Value::Atom(ref a) => Value::Ref(a.clone()),
+            _ => unreachable!(),
+        };
+        let watches = atom_cell(&cell)?.watches.borrow().clone();
+        for (k, f) in watches {
+            f.apply(vec![k, r.clone(), old.clone(), new.clone()])?;
+        }
+    }
+    Ok(true)
+}
+
+// (dosync* f) runs f in a transaction, or in the running one when nested
+fn dosync(a: MalArgs) -> MalRet {
+    if in_tx() {
+        return a[0].apply(vec![]);
+    }
+    for _ in 0..MAX_RETRIES {
+        let tx = Tx {
+            read_point: CLOCK.load(Ordering::SeqCst),
+            doomed: false,
+            refs: FnvHashMap::default(),
+            commutes: FnvHashMap::default(),
+        };
+        TX.with(|t| *t.borrow_mut() = Some(tx));
+        let res = a[0].apply(vec![]);
+        let tx = TX.with(|tx| tx.borrow_mut().take()).unwrap();
+        if tx.doomed {
+            continue;
+        }
+        let v = res?;
+        if commit(tx)? {
+            return Ok(v);
+        }
+    }
+    error(&format!(
+        "dosync: transaction still conflicting after {} retries",
+        MAX_RETRIES
+    ))
+}
+
+fn make_ref(a: MalArgs) -> MalRet {
+    let cell = Atom::new(vector(vec![Number(0.0), a[0].clone()]));
+    track_atom(&Value::Atom(cell.clone()));
+    Ok(Value::Ref(cell))
+}
+
+// Futures, pmap and pcalls, with the "threads" feature
+//
+// Each runs its functions on other OS threads. Values, namespaces,
+// multimethods, protocols and loaded modules are shared by all threads. A
+// thread starts out with the current namespace, dynamic bindings, handlers
+// and limits of the thread that started it, like the binding conveyance of
+// Clojure. Restarts, transactions and go blocks stay on the thread that
+// established them.
+
+// The state of the thread running mal code that the threads it starts take on
+#[cfg(feature = "threads")]
+#[derive(Clone)]
+struct Conveyed {
+    ns: String,
+    bindings: Vec<(String, Value)>,
+    handlers: Vec<(Value, Value, usize)>,
+    print_limits: PrintLimits,
+    limits: Inherited,
+}
+
+#[cfg(feature = "threads")]
+impl Conveyed {
+    fn capture() -> Conveyed {
+        Conveyed {
+            ns: current_ns(),
+            bindings: bindings(),
+            handlers: HANDLERS.with(|h| h.borrow().clone()),
+            print_limits: print_limits(),
+            limits: inherited(),
+        }
+    }
+
+    // Takes the state on in the new thread, before it runs any mal code
+    fn install(self) {
+        let handlers = self.handlers;
+        set_current_ns(&self.ns);
+        push_bindings(self.bindings);
+        HANDLERS.with(|h| *h.borrow_mut() = handlers);
+        set_print_limits(self.print_limits);
+        inherit(self.limits);
+    }
+}
+
+// Calls f, turning a panic of the thread into an error
+#[cfg(feature = "threads")]
+fn call_caught(f: &Value, args: MalArgs) -> MalRet {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f.apply(args)))
+        .unwrap_or_else(|_| error("thread panicked"))
+}
+
+// (future-call f) runs f on a new thread, deref waits for its result
+#[cfg(feature = "threads")]
+fn future_call(a: MalArgs) -> MalRet {
+    let future = Shared::new(Future::new());
+    let (f, result) = (a[0].clone(), future.clone());
+    let (state, worker) = (Conveyed::capture(), Worker::new());
+    std::thread::Builder::new()
+        .spawn(move || {
+            let _worker = worker;
+            state.install();
+            result.finish(call_caught(&f, vec![]));
+        })
+        .map_err(|e| ErrString(format!("future: {}", e)))?;
+    Ok(Value::Future(future))
+}
+
+#[cfg(feature = "threads")]
+fn future_done(a: MalArgs) -> MalRet {
+    match a[0] {
+        Value::Future(ref f) => Ok(Boolean(f.is_done())),
+        _ => error("future-done?: expected a future"),
+    }
+}
+
+// Makes the calls on as many threads as there are cores, or fewer, and
+// returns their results in order
+#[cfg(feature = "threads")]
+fn call_parallel(calls: Vec<(Value, MalArgs)>) -> Result<Vec<Value>, MalErr> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(calls.len());
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<MalRet>>> = calls.iter().map(|_| Mutex::new(None)).collect();
+    let state = Conveyed::capture();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let (state, worker) = (state.clone(), Worker::new());
+            let (calls, next, results) = (&calls, &next, &results);
+            scope.spawn(move || {
+                let _worker = worker;
+                state.install();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let (f, args) = match calls.get(i) {
+                        Some(call) => call,
+                        None => break,
+                    };
+                    let res = call_caught(f, args.clone());
+                    *results[i].lock().unwrap_or_else(|e| e.into_inner()) = Some(res);
+                }
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| {
+            r.into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+                .unwrap_or_else(|| error("thread panicked"))
+        })
+        .collect()
+}
+
+// Applies f to the elements on a few threads, keeping the order
+#[cfg(feature = "threads")]
+fn pmap(a: MalArgs) -> MalRet {
+    realize(&a[1])?;
+    let seq = match (a[1].strip(), a[1].seq()) {
+        (_, Some(v)) => v,
+        (Null, _) => vec![],
+        _ => return error("pmap: expected a sequence"),
+    };
+    let calls = seq.into_iter().map(|v| (a[0].clone(), vec![v])).collect();
+    Ok(list(call_parallel(calls)?))
+}
+
+// Calls the functions on a few threads and returns their results in order
+#[cfg(feature = "threads")]
+fn pcalls(a: MalArgs) -> MalRet {
+    let calls = a.into_iter().map(|f| (f, vec![])).collect();
+    Ok(list(call_parallel(calls)?))
+}
+
+fn deref(a: MalArgs) -> MalRet {
+    #[cfg(feature = "threads")]
+    {
+        if let Value::Future(ref f) = a[0] {
+            return f.wait();
+        }
+    }
+    if let Some(cell) = ref_cell(&a[0]) {
+        return ref_deref(&cell);
+    }
+    a[0].deref()
+}
+
+fn in_ns_fn(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Symbol(ref s)) | Some(Value::String(ref s)) => {
+            in_ns(s);
+            Ok(Null)
+        }
+        _ => error("in-ns: expected a namespace name"),
+    }
+}
+
+pub fn ns() -> Vec<(&'static str, Value)> {
+    #[cfg_attr(not(feature = "threads"), allow(unused_mut))]
+    let mut ns = vec![
+        (
+            "=",
+            func(|a| {
+                let all = PrintLimits::default();
+                realize_deep(&a[0], &all, 0, &mut vec![])?;
+                realize_deep(&a[1], &all, 0, &mut vec![])?;
+                Ok(Boolean(a[0].equals(&a[1])))
+            }),
+        ),
+        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
+        ("nil?", func(fn_is_type!(Null))),
+        ("true?", func(fn_is_type!(Boolean(true)))),
+        ("false?", func(fn_is_type!(Boolean(false)))),
+        ("symbol", func(symbol)),
+        ("symbol?", func(fn_is_type!(Symbol(_)))),
+        (
+            "string?",
+            func(fn_is_type!(Value::String(ref s) if !s.starts_with("\u{29e}"))),
+        ),
+        ("keyword", func(|a| a[0].keyword())),
+        (
+            "keyword?",
+            func(fn_is_type!(Value::String(ref s) if s.starts_with("\u{29e}"))),
+        ),
+        ("number?", func(fn_is_type!(Number(_)))),
+        ("char", func(to_char)),
+        ("char?", func(fn_is_type!(Char(_)))),
+        ("int", func(int)),
+        (
+            "whitespace?",
+            func(fn_is_type!(Char(c) if c.is_whitespace())),
+        ),
+        ("digit?", func(fn_is_type!(Char(c) if c.is_ascii_digit()))),
+        ("letter?", func(fn_is_type!(Char(c) if c.is_alphabetic()))),
+        (
+            "fn?",
+            func(fn_is_type!(Value::Closure(c) if !c.is_macro, Value::Func(_), Value::Cont(_))),
+        ),
+        ("macro?", func(fn_is_type!(Value::Closure(c) if c.is_macro))),
+        (
+            "pr-str",
+            func(|a| {
+                realize_printed(&a)?;
+                Ok(Value::String(pr_sized(&a, true, " ")?))
+            }),
+        ),
+        (
+            "str",
+            func(|a| {
+                realize_printed(&a)?;
+                Ok(Value::String(pr_sized(&a, false, "")?))
+            }),
+        ),
+        (
+            "prn",
+            func(|a| {
+                realize_printed(&a)?;
+                println!("{}", pr_seq(&a, true, "", "", " "));
+                Ok(Null)
+            }),
+        ),
+        (
+            "println",
+            func(|a| {
+                realize_printed(&a)?;
+                println!("{}", pr_seq(&a, false, "", "", " "));
+                Ok(Null)
+            }),
+        ),
+        ("pr-str-pretty", func(|a| Ok(Value::String(pretty(&a)?)))),
+        (
+            "pprint",
+            func(|a| {
+                println!("{}", pretty(&a)?);
+                Ok(Null)
+            }),
+        ),
+        ("read-string", func(fn_str!(read_string))),
+        ("readline", func(readline)),
+        ("slurp", func(fn_str!(slurp))),
+        ("re-pattern", func(re_pattern)),
+        ("re-find", func(re_find)),
+        ("<", func(fn_t_num_num!(Boolean, |i, j| { i < j }))),
+        ("<=", func(fn_t_num_num!(Boolean, |i, j| { i <= j }))),
+        (">", func(fn_t_num_num!(Boolean, |i, j| { i > j }))),
+        (">=", func(fn_t_num_num!(Boolean, |i, j| { i >= j }))),
+        ("+", func(fn_t_num_num!(Number, |i, j| { i + j }))),
+        ("-", func(fn_t_num_num!(Number, |i, j| { i - j }))),
+        ("*", func(fn_t_num_num!(Number, |i, j| { i * j }))),
+        ("/", func(divide)),
+        ("time-ms", func(time_ms)),
+        (
+            "sequential?",
+            func(fn_is_type!(List(_), Value::Vec(_), Value::Lazy(_))),
+        ),
+        (
+            "list",
+            func(|a| {
+                check_size(a.len())?;
+                Ok(list(a))
+            }),
+        ),
+        ("list?", func(fn_is_type!(List(_)))),
+        (
+            "vector",
+            func(|a| {
+                check_size(a.len())?;
+                Ok(vector(a))
+            }),
+        ),
+        ("vector?", func(fn_is_type!(Value::Vec(_)))),
+        (
+            "hash-map",
+            func(|a| {
+                check_size(a.len() / 2)?;
+                hash_map(a)
+            }),
+        ),
+        ("map?", func(fn_is_type!(Map(_)))),
+        ("assoc", func(assoc)),
+        ("dissoc", func(dissoc)),
+        ("get", func(get)),
+        ("contains?", func(contains_q)),
+        ("keys", func(keys)),
+        ("vals", func(vals)),
+        ("vec", func(vec)),
+        ("cons", func(cons)),
+        ("concat", func(concat)),
+        ("empty?", func(|a| a[0].empty_q())),
+        ("nth", func(nth)),
+        ("first", func(first)),
+        ("rest", func(rest)),
+        (
+            "count",
+            func(|a| {
+                realize(&a[0])?;
+                a[0].count()
+            }),
+        ),
+        ("apply", func(apply)),
+        ("map", func(map)),
+        ("conj", func(conj)),
+        ("seq", func(seq)),
+        (
+            "lazy-seq*",
+            func(|a| Ok(lazy_seq(Lazy::Thunk(a[0].clone())))),
+        ),
+        ("meta", func(meta)),
+        ("with-meta", func(|a| a[0].with_meta(&a[1]))),
+        (
+            "atom",
+            func(|a| {
+                let v = atom(&a[0]);
+                track_atom(&v);
+                Ok(v)
+            }),
+        ),
+        ("atom?", func(fn_is_type!(Value::Atom(_)))),
+        ("deref", func(deref)),
+        (
+            "reset!",
+            func(|a| {
+                set_atom(&a[0], a[1].clone())?;
+                Ok(a[1].clone())
+            }),
+        ),
+        ("swap!", func(|a| Ok(swap(&a)?.1))),
+        (
+            "reset-vals!",
+            func(|a| Ok(vector(vec![set_atom(&a[0], a[1].clone())?, a[1].clone()]))),
+        ),
+        (
+            "swap-vals!",
+            func(|a| {
+                let (old, new) = swap(&a)?;
+                Ok(vector(vec![old, new]))
+            }),
+        ),
+        ("compare-and-set!", func(compare_and_set)),
+        ("add-watch", func(add_watch)),
+        ("remove-watch", func(remove_watch)),
+        ("set-validator!", func(set_validator)),
+        ("get-validator", func(get_validator)),
+        ("ref", func(make_ref)),
+        ("dosync*", func(dosync)),
+        ("ref-set", func(ref_set)),
+        ("alter", func(alter)),
+        ("commute", func(commute)),
+        ("ensure", func(ensure)),
+        ("in-ns", func(in_ns_fn)),
+        ("apropos", func(apropos)),
+        ("multifn-dispatch", func(multifn_dispatch)),
+        ("remove-method", func(remove_method)),
+        ("prefer-method", func(prefer_method)),
+        ("methods", func(methods)),
+        (
+            "get-method",
+            func(|a| with_multifn(&multifn_name(&a[0])?, |m| find_method(m, &a[1]))),
+        ),
+        ("derive", func(derive)),
+        ("type", func(|a| Ok(Symbol(type_name(&a[0]))))),
+        ("protocol-dispatch", func(protocol_dispatch)),
+        ("satisfies?", func(satisfies)),
+        ("make-record", func(make_record)),
+        ("signal", func(|a| signal(&a[0]))),
+        (
+            "chan",
+            func(|a| match a.first().map(int_value) {
+                Some(Some(n)) if n >= 0 => Ok(new_chan(n as usize)),
+                None => Ok(new_chan(0)),
+                _ => error("chan: expected a buffer size"),
+            }),
+        ),
+        (
+            "close!",
+            func(|a| {
+                close(&*chan_of(&a[0])?);
+                Ok(Null)
+            }),
+        ),
+        ("timeout", func(timeout)),
+        ("go*", func(go)),
+        ("check-go", func(check_go)),
+        ("chan-take", func(chan_take)),
+        ("chan-put", func(chan_put)),
+        ("chan-alts", func(chan_alts)),
+        ("<!!", func(blocking_take)),
+        ("invoke-restart", func(invoke_restart)),
+        (
+            "compute-restarts",
+            func(|_| {
+                Ok(list(RESTARTS.with(|r| {
+                    r.borrow()
+                        .iter()
+                        .rev()
+                        .map(|(n, _)| Symbol(n.to_string()))
+                        .collect()
+                })))
+            }),
+        ),
+        (
+            "record?",
+            func(|a| Ok(Boolean(!BUILTIN_TYPES.contains(&type_name(&a[0]).as_str())))),
+        ),
+        ("isa?", func(|a| Ok(Boolean(isa(&a[0], &a[1]))))),
+        ("ex-info", func(ex_info)),
+        (
+            "gensym",
+            func(|a| match a.first() {
+                Some(Value::String(ref p)) => Ok(Symbol(gensym(p))),
+                None => Ok(Symbol(gensym("G__"))),
+                _ => error("gensym: expected a prefix string"),
+            }),
+        ),
+        ("ex-data", func(|a| ex_field(&a, "data"))),
+        ("ex-message", func(ex_message)),
+    ];
+    #[cfg(feature = "threads")]
+    ns.extend(vec![
+        ("future-call", func(future_call)),
+        ("future-done?", func(future_done)),
+        ("pmap", func(pmap)),
+        ("pcalls", func(pcalls)),
+    ]);
+    ns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc::tests::lock_registries;
+
+    fn inc(a: MalArgs) -> MalRet {
+        match a[0] {
+            Number(n) => Ok(Number(n + 1.0)),
+            _ => error("inc: expected a number"),
+        }
+    }
+
+    thread_local! {
+        static REFS: RefCell<Vec<Value>> = const { RefCell::new(vec![]) };
+    }
+
+    fn alter_both(_: MalArgs) -> MalRet {
+        for r in REFS.with(|r| r.borrow().clone()) {
+            alter(vec![r, func(inc)])?;
+        }
+        Ok(Null)
+    }
+
+    #[test]
+    fn test_failed_validator_leaves_refs() {
+        let _registries = lock_registries();
+        let refs = vec![
+            make_ref(vec![Number(0.0)]).unwrap(),
+            make_ref(vec![Number(0.0)]).unwrap(),
+        ];
+        set_validator(vec![
+            refs[1].clone(),
+            func(|a| Ok(Boolean(a[0] == Number(0.0)))),
+        ])
+        .unwrap();
+        REFS.with(|r| *r.borrow_mut() = refs.clone());
+        assert!(dosync(vec![func(alter_both)]).is_err());
+        for r in refs {
+            assert_eq!(deref(vec![r]).unwrap(), Number(0.0));
+        }
+    }
+
+    // A commute function starting a transaction of its own when the commit
+    // applies it again
+    fn inc_in_tx(a: MalArgs) -> MalRet {
+        dosync(vec![func(|_| Ok(Null))])?;
+        inc(a)
+    }
+
+    fn commute_first(_: MalArgs) -> MalRet {
+        let r = REFS.with(|r| r.borrow()[0].clone());
+        commute(vec![r, func(inc_in_tx)])
+    }
+
+    #[test]
+    fn test_commute_runs_outside_the_commit_lock() {
+        let _registries = lock_registries();
+        let r = make_ref(vec![Number(0.0)]).unwrap();
+        REFS.with(|refs| *refs.borrow_mut() = vec![r.clone()]);
+        assert_eq!(dosync(vec![func(commute_first)]), Ok(Number(1.0)));
+        assert_eq!(deref(vec![r]).unwrap(), Number(1.0));
+    }
+
+    #[test]
+    fn test_maps_are_not_refs() {
+        let m = hash_map(vec![keyword("mal/ref"), atom(&Number(5.0))]).unwrap();
+        assert!(deref(vec![m.clone()]).is_err());
+        assert_eq!(alter(vec![m, func(inc)]), error("alter: expected a ref"));
+    }
+
+    #[cfg(feature = "threads")]
+    mod threads {
+        use super::*;
+        use std::sync::{Barrier, OnceLock};
+        use std::thread;
+
+        // The other transaction commits between the two waits, while the
+        // first attempt of the one under test is running
+        static BARRIER: Barrier = Barrier::new(2);
+
+        fn conflict(body: fn(MalArgs) -> MalRet, other: fn(MalArgs) -> MalRet) -> MalRet {
+            let t = thread::spawn(move || {
+                BARRIER.wait();
+                let v = dosync(vec![func(other)]);
+                BARRIER.wait();
+                v
+            });
+            let v = dosync(vec![func(body)]);
+            t.join().unwrap()?;
+            v
+        }
+
+        static COUNTER: OnceLock<Value> = OnceLock::new();
+        static WRITE_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+        fn alter_counter(_: MalArgs) -> MalRet {
+            alter(vec![COUNTER.get().unwrap().clone(), func(inc)])
+        }
+
+        fn alter_counter_slowly(_: MalArgs) -> MalRet {
+            let r = COUNTER.get().unwrap().clone();
+            deref(vec![r.clone()])?;
+            if WRITE_ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                BARRIER.wait();
+                BARRIER.wait();
+            }
+            alter(vec![r, func(inc)])
+        }
+
+        #[test]
+        fn test_write_conflict_retries() {
+            let _registries = lock_registries();
+            COUNTER.set(make_ref(vec![Number(0.0)]).unwrap()).unwrap();
+            let v = conflict(alter_counter_slowly, alter_counter).unwrap();
+            assert_eq!(v, Number(2.0));
+            assert_eq!(WRITE_ATTEMPTS.load(Ordering::SeqCst), 2);
+            assert_eq!(
+                deref(vec![COUNTER.get().unwrap().clone()]).unwrap(),
+                Number(2.0)
+            );
+        }
+
+        static PAIR: OnceLock<(Value, Value)> = OnceLock::new();
+        static READ_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+        fn alter_pair(_: MalArgs) -> MalRet {
+            let (a, b) = PAIR.get().unwrap().clone();
+            alter(vec![a, func(inc)])?;
+            alter(vec![b, func(inc)])
+        }
+
+        fn read_pair_slowly(_: MalArgs) -> MalRet {
+            let (a, b) = PAIR.get().unwrap().clone();
+            let a = deref(vec![a])?;
+            if READ_ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 {
+                BARRIER.wait();
+                BARRIER.wait();
+            }
+            Ok(vector(vec![a, deref(vec![b])?]))
+        }
+
+        #[test]
+        fn test_reads_come_from_one_snapshot() {
+            let _registries = lock_registries();
+            let pair = (
+                make_ref(vec![Number(0.0)]).unwrap(),
+                make_ref(vec![Number(0.0)]).unwrap(),
+            );
+            PAIR.set(pair).unwrap();
+            let v = conflict(read_pair_slowly, alter_pair).unwrap();
+            assert_eq!(v, vector(vec![Number(1.0), Number(1.0)]));
+            assert_eq!(READ_ATTEMPTS.load(Ordering::SeqCst), 2);
+        }
+    }
 }
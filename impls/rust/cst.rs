@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::error::Error;
+use crate::printer::map_key;
+use crate::reader::{anon_fn, atom_value, tagged_literal, Lexer, Token, TokenType};
+use crate::types::Value;
+
+// A node of the concrete syntax tree. Unlike Value it keeps whitespace and
+// comments, so printing the nodes read from some source gives back exactly
+// that source. Tools can edit the nodes and print them without losing any
+// of the formatting around the parts they did not touch.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Node {
+    // Whitespace or a comment
+    Trivia(TokenType, String),
+    // A single token such as a number, string or symbol
+    Atom(TokenType, String),
+    // A list, vector, map or #(...), with the token that opened it
+    Seq(TokenType, Vec<Node>),
+}
+
+impl Node {
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Node::Trivia(..))
+    }
+
+    // Returns the value the node stands for, or None for trivia. #_ and tags
+    // only have a value together with the form after them, see to_values.
+    pub fn to_value(&self) -> Option<Value> {
+        match self {
+            Node::Trivia(..) => None,
+            Node::Atom(TokenType::Discard, _) | Node::Atom(TokenType::Tag, _) => None,
+            Node::Atom(token_type, text) => Some(
+                atom_value(token_type, text)
+                    .unwrap_or_else(|| Value::Error("invalid number".to_string())),
+            ),
+            Node::Seq(open, children) => {
+                let values = to_values(children).into_iter();
+                Some(match open {
+                    TokenType::AnonFn => anon_fn(values.collect()),
+                    TokenType::LeftBracket => Value::Vec(values.collect()),
+                    TokenType::LeftBrace => {
+                        let mut map = HashMap::new();
+                        let mut values = values;
+                        while let (Some(k), Some(v)) = (values.next(), values.next()) {
+                            map.insert(map_key(&k), v);
+                        }
+                        Value::Map(map)
+                    }
+                    _ => Value::List(values.collect()),
+                })
+            }
+        }
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Trivia(_, text) | Node::Atom(_, text) => write!(f, "{}", text),
+            Node::Seq(open, children) => {
+                write!(f, "{}", open)?;
+                for child in children.iter() {
+                    write!(f, "{}", child)?;
+                }
+                write!(f, "{}", closing(open))
+            }
+        }
+    }
+}
+
+// Returns the token that closes a sequence opened with `open`
+fn closing(open: &TokenType) -> TokenType {
+    match open {
+        TokenType::LeftBracket => TokenType::RightBracket,
+        TokenType::LeftBrace => TokenType::RightBrace,
+        _ => TokenType::RightParen,
+    }
+}
+
+struct Builder<'a> {
+    lexer: Lexer<'a>,
+    input: &'a str,
+}
+
+impl Builder<'_> {
+    // Reads nodes up to the token closing `open`, or to the end of the input
+    // when there is no `open`
+    fn nodes(&mut self, open: Option<&Token>) -> Result<Vec<Node>, Error> {
+        let close = open.map(|token| closing(token.token_type()));
+        let mut nodes = Vec::new();
+        loop {
+            let token = self.lexer.next()?;
+            let token_type = token.token_type().clone();
+            let text = token.text(self.input).to_string();
+            match token_type {
+                ref t if Some(t) == close.as_ref() => return Ok(nodes),
+                TokenType::EOF => {
+                    return match open {
+                        Some(open) => Err(Error::Unbalanced(
+                            open.token_type().clone(),
+                            open.location(self.input),
+                        )),
+                        None => Ok(nodes),
+                    }
+                }
+                TokenType::Whitespace | TokenType::Comment => {
+                    nodes.push(Node::Trivia(token_type, text))
+                }
+                TokenType::LeftParen
+                | TokenType::LeftBracket
+                | TokenType::LeftBrace
+                | TokenType::AnonFn => {
+                    let children = self.nodes(Some(&token))?;
+                    let forms = children.iter().filter(|n| !n.is_trivia()).count();
+                    if token_type == TokenType::LeftBrace && forms % 2 != 0 {
+                        return Err(Error::OddMapForms(token.location(self.input)));
+                    }
+                    nodes.push(Node::Seq(token_type, children));
+                }
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    return Err(Error::UnexpectedToken(
+                        token_type,
+                        token.location(self.input),
+                    ))
+                }
+                TokenType::Number if atom_value(&token_type, &text).is_none() => {
+                    return Err(Error::InvalidNumber(text, token.location(self.input)))
+                }
+                _ => nodes.push(Node::Atom(token_type, text)),
+            }
+        }
+    }
+}
+
+// Reads the input into a lossless syntax tree, one node per top level form or
+// piece of trivia between them
+pub fn read(input: &str) -> Result<Vec<Node>, Error> {
+    let mut builder = Builder {
+        lexer: Lexer::with_trivia(input),
+        input,
+    };
+    builder.nodes(None)
+}
+
+// Prints the nodes back to source
+pub fn print(nodes: &[Node]) -> String {
+    nodes.iter().map(|node| node.to_string()).collect()
+}
+
+// Returns the values of the forms in the nodes, dropping the trivia and
+// applying #_ and tags to the forms after them
+pub fn to_values(nodes: &[Node]) -> Vec<Value> {
+    let mut values = Vec::new();
+    // The #_ and tags still waiting for their form, innermost last
+    let mut pending: Vec<&Node> = Vec::new();
+    for node in nodes.iter() {
+        match node {
+            Node::Atom(TokenType::Discard, _) | Node::Atom(TokenType::Tag, _) => {
+                pending.push(node);
+                continue;
+            }
+            Node::Trivia(..) => continue,
+            _ => (),
+        }
+        let mut value = node.to_value();
+        while let Some(prefix) = pending.pop() {
+            match (prefix, value) {
+                // The outer prefixes apply to the next form instead
+                (Node::Atom(TokenType::Discard, _), _) => {
+                    value = None;
+                    break;
+                }
+                (Node::Atom(_, tag), Some(form)) => {
+                    value = Some(
+                        tagged_literal(&tag[1..], form)
+                            .unwrap_or_else(|_| Value::Error("invalid #inst".to_string())),
+                    );
+                }
+                (_, v) => value = v,
+            }
+        }
+        values.extend(value);
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+
+    const SOURCE: &str = "; square a number\n(def square\n  (fn [x] ; the argument\n    (* x x)))\n\n\t{\"a\" 1  \"b\" [2 3]}  ; trailing\n#(+ % %2) #_ #_ (a) b #inst \"2000-01-01\" #my/tag [1] #\"\\d+\"\n";
+
+    #[test]
+    fn test_round_trip() {
+        let nodes = read(SOURCE).unwrap();
+        assert_eq!(print(&nodes), SOURCE);
+        assert!(nodes[0].is_trivia());
+    }
+
+    #[test]
+    fn test_to_values() {
+        let nodes = read(SOURCE).unwrap();
+        let (values, errors) = reader::read_all_recovering(SOURCE);
+        assert!(errors.is_empty());
+        assert_eq!(to_values(&nodes), values);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut nodes = read("(def square ; keep me\n  2)").unwrap();
+        if let Node::Seq(_, ref mut children) = nodes[0] {
+            children[2] = Node::Atom(TokenType::Identifier, "sq".to_string());
+        }
+        assert_eq!(print(&nodes), "(def sq ; keep me\n  2)");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            read("(a ; open\n"),
+            Err(Error::Unbalanced(TokenType::LeftParen, _))
+        ));
+        assert!(matches!(
+            read("[a)"),
+            Err(Error::UnexpectedToken(TokenType::RightParen, _))
+        ));
+    }
+}
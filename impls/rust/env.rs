@@ -1,50 +1,64 @@
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashMap;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
+use crate::gc::track_env;
+use crate::sync::{Lock, Shared};
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::Value::{Boolean, Map, Null, Symbol};
+use crate::types::{error, key_string, keyword, list, MalErr, MalRet, Value};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    data: Lock<FnvHashMap<String, Value>>,
     pub outer: Option<Env>,
+    // The name of the namespace when this is the top level env of one
+    pub ns: Option<String>,
 }
 
-pub type Env = Rc<EnvStruct>;
+pub type Env = Shared<EnvStruct>;
 
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
-    Rc::new(EnvStruct {
-        data: RefCell::new(FnvHashMap::default()),
-        outer: outer,
-    })
+    let env = Shared::new(EnvStruct {
+        data: Lock::new(FnvHashMap::default()),
+        outer,
+        ns: None,
+    });
+    track_env(&env);
+    env
 }
 
-// TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+pub fn env_bind(outer: Option<Env>, mbinds: &Value, exprs: Vec<Value>) -> Result<Env, MalErr> {
     let env = env_new(outer);
-    match mbinds {
-        List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
-                    }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
-                    }
-                }
-            }
-            Ok(env)
-        }
-        _ => Err(ErrString("env_bind binds not List/Vector".to_string())),
+    let binds = match mbinds.seq() {
+        Some(binds) => binds,
+        None => return Err(ErrString("env_bind binds not List/Vector".to_string())),
+    };
+    let variadic = binds.iter().position(|b| b == &Symbol("&".to_string()));
+    let arity = variadic.unwrap_or(binds.len());
+    if exprs.len() < arity || (variadic.is_none() && exprs.len() > arity) {
+        return error(&format!(
+            "wrong number of arguments: expected {}{}, got {}",
+            arity,
+            if variadic.is_some() { " or more" } else { "" },
+            exprs.len()
+        ));
+    }
+    let mut exprs = exprs.into_iter();
+    for b in binds[..arity].iter() {
+        env_set(&env, b.clone(), exprs.next().unwrap())?;
+    }
+    if let Some(i) = variadic {
+        match binds.get(i + 1) {
+            Some(rest) => env_set(&env, rest.clone(), list(exprs.collect()))?,
+            None => return error("expected a parameter after &"),
+        };
     }
+    Ok(env)
 }
 
 pub fn env_find(env: &Env, key: &str) -> Option<Env> {
@@ -55,24 +69,31 @@ pub fn env_find(env: &Env, key: &str) -> Option<Env> {
     }
 }
 
-pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
+pub fn env_get(env: &Env, key: &Value) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
-            _ => error(&format!("'{}' not found", s)),
+        Symbol(ref s) => match env_find(env, s) {
+            Some(e) => {
+                if let Some(v) = e.ns.as_ref().and_then(|ns| bound_value(ns, s)) {
+                    return Ok(v);
+                }
+                Ok(e.data
+                    .borrow()
+                    .get(s)
+                    .ok_or(ErrString(format!("'{}' not found", s)))?
+                    .clone())
+            }
+            _ => match split_qualified(s) {
+                Some((ns, name)) => ns_get(ns, name),
+                None => error(&format!("'{}' not found", s)),
+            },
         },
         _ => error("Env.get called with non-Str"),
     }
 }
 
-pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
+pub fn env_set(env: &Env, key: Value, val: Value) -> MalRet {
     match key {
-        Sym(ref s) => {
+        Symbol(ref s) => {
             env.data.borrow_mut().insert(s.to_string(), val.clone());
             Ok(val)
         }
@@ -80,6 +101,314 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     }
 }
 
-pub fn env_sets(env: &Env, key: &str, val: MalVal) {
+pub fn env_sets(env: &Env, key: &str, val: Value) {
     env.data.borrow_mut().insert(key.to_string(), val);
 }
+
+// Calls f with each value bound in the env, for the cycle collector
+pub fn env_visit(env: &Env, f: &mut dyn FnMut(&Value)) {
+    for v in env.data.borrow().values() {
+        f(v)
+    }
+}
+
+// Drops all the bindings of the env, the values are dropped after the
+// borrow ends in case dropping them reaches this env again
+pub fn env_clear(env: &Env) {
+    let data = std::mem::take(&mut *env.data.borrow_mut());
+    drop(data);
+}
+
+// Namespaces
+
+pub const CORE_NS: &str = "mal.core";
+
+global! {
+    // The top level env of every namespace by name. All of them have the
+    // mal.core env as outer, so the core functions are found from anywhere.
+    static NAMESPACES: Lock<FnvHashMap<String, Env>> = Lock::new(FnvHashMap::default());
+    // The aliases given with :as, per namespace
+    static ALIASES: Lock<FnvHashMap<String, FnvHashMap<String, String>>> =
+        Lock::new(FnvHashMap::default());
+    // The metadata of the vars defined at the top level of namespaces, by ns/name
+    static VAR_META: Lock<FnvHashMap<String, Value>> = Lock::new(FnvHashMap::default());
+}
+
+thread_local! {
+    static CURRENT_NS: RefCell<String> = RefCell::new("user".to_string());
+    // The values binding forms being evaluated on this thread give to
+    // dynamic vars, by ns/name, innermost last
+    static BINDINGS: RefCell<Vec<(String, Value)>> = const { RefCell::new(vec![]) };
+}
+
+// Returns the top level env of the namespace, creating it on first use
+pub fn ns_env(name: &str) -> Env {
+    if let Some(env) = NAMESPACES.with(|nss| nss.borrow().get(name).cloned()) {
+        return env;
+    }
+    let outer = if name == CORE_NS {
+        None
+    } else {
+        Some(ns_env(CORE_NS))
+    };
+    let env = Shared::new(EnvStruct {
+        data: Lock::new(FnvHashMap::default()),
+        outer,
+        ns: Some(name.to_string()),
+    });
+    track_env(&env);
+    // Defines *ns*, whose value comes from CURRENT_NS, see bound_value
+    if name == CORE_NS {
+        env_sets(&env, "*ns*", Symbol(current_ns()));
+    }
+    NAMESPACES.with(|nss| nss.borrow_mut().insert(name.to_string(), env.clone()));
+    env
+}
+
+pub fn ns_exists(name: &str) -> bool {
+    NAMESPACES.with(|nss| nss.borrow().contains_key(name))
+}
+
+pub fn current_ns() -> String {
+    CURRENT_NS.with(|ns| ns.borrow().clone())
+}
+
+// Makes the namespace current on this thread, for a thread taking on the
+// namespace of the thread that started it
+#[cfg(feature = "threads")]
+pub fn set_current_ns(name: &str) {
+    CURRENT_NS.with(|ns| *ns.borrow_mut() = name.to_string());
+}
+
+// Makes the namespace current, creating it if needed, and returns its env
+pub fn in_ns(name: &str) -> Env {
+    let env = ns_env(name);
+    CURRENT_NS.with(|ns| *ns.borrow_mut() = name.to_string());
+    env
+}
+
+// Makes `alias/x` mean `target/x` in the current namespace
+pub fn ns_alias(alias: &str, target: &str) {
+    ALIASES.with(|aliases| {
+        aliases
+            .borrow_mut()
+            .entry(current_ns())
+            .or_insert_with(FnvHashMap::default)
+            .insert(alias.to_string(), target.to_string());
+    });
+}
+
+// Copies the given symbols of the target namespace into the current one
+pub fn ns_refer(target: &str, syms: &[Value]) -> MalRet {
+    let env = ns_env(&current_ns());
+    for sym in syms.iter() {
+        match sym {
+            Symbol(ref s) => env_sets(&env, s, ns_get(target, s)?),
+            _ => return error("refer: expected a symbol"),
+        }
+    }
+    Ok(Null)
+}
+
+// Splits an argument of require, either foo.bar or [foo.bar :as b :refer [x y]],
+// into the namespace name and the options
+fn require_spec(spec: &Value) -> Result<(String, Vec<Value>), MalErr> {
+    match (spec, spec.seq()) {
+        (Symbol(ref s), _) => Ok((s.clone(), vec![])),
+        (_, Some(v)) => match v.first() {
+            Some(Symbol(ref s)) => Ok((s.clone(), v[1..].to_vec())),
+            _ => Err(ErrString("require: expected a namespace name".to_string())),
+        },
+        _ => Err(ErrString(
+            "require: expected a symbol or vector".to_string(),
+        )),
+    }
+}
+
+// Returns the namespace an argument of require is about
+pub fn require_name(spec: &Value) -> Result<String, MalErr> {
+    Ok(require_spec(spec)?.0)
+}
+
+// Applies the :as and :refer options of an argument of require. The
+// namespace has to exist already, see loader::require.
+pub fn ns_require(spec: &Value) -> MalRet {
+    let (name, opts) = require_spec(spec)?;
+    if !ns_exists(&name) {
+        return error(&format!("require: no namespace '{}'", name));
+    }
+    for opt in opts.chunks(2) {
+        match opt {
+            [k, Symbol(ref alias)] if k == &keyword("as") => ns_alias(alias, &name),
+            [k, syms] if k == &keyword("refer") && syms.seq().is_some() => {
+                ns_refer(&name, &syms.seq().unwrap())?;
+            }
+            _ => return error("require: expected :as alias or :refer [symbols]"),
+        }
+    }
+    Ok(Null)
+}
+
+// Splits foo.bar/baz into its namespace and name. A lone / is not qualified.
+fn split_qualified(s: &str) -> Option<(&str, &str)> {
+    match s.find('/') {
+        Some(i) if i > 0 && i < s.len() - 1 => Some((&s[..i], &s[i + 1..])),
+        _ => None,
+    }
+}
+
+// Returns the namespace an alias of the current namespace stands for, or
+// the name itself when it is not an alias
+fn resolve_alias(ns: &str) -> String {
+    ALIASES
+        .with(|aliases| {
+            aliases
+                .borrow()
+                .get(&current_ns())
+                .and_then(|a| a.get(ns).cloned())
+        })
+        .unwrap_or_else(|| ns.to_string())
+}
+
+// Looks a name up in a namespace, or in the namespace an alias of the
+// current one stands for. Only the namespace's own definitions are visible.
+fn ns_get(ns: &str, name: &str) -> MalRet {
+    let target = resolve_alias(ns);
+    if let Some(v) = bound_value(&target, name) {
+        return Ok(v);
+    }
+    let env = match NAMESPACES.with(|nss| nss.borrow().get(&target).cloned()) {
+        Some(env) => env,
+        None => return error(&format!("no namespace '{}'", ns)),
+    };
+    let val = env.data.borrow().get(name).cloned();
+    match val {
+        Some(v) => Ok(v),
+        None => error(&format!("'{}/{}' not found", ns, name)),
+    }
+}
+
+// Vars
+
+// Returns the qualified name, ns/name, of the var a symbol refers to
+pub fn resolve_var(env: &Env, sym: &Value) -> Option<String> {
+    let s = match sym {
+        Symbol(ref s) => s,
+        _ => return None,
+    };
+    match env_find(env, s) {
+        Some(e) => e.ns.as_ref().map(|ns| format!("{}/{}", ns, s)),
+        None => split_qualified(s).map(|(ns, name)| format!("{}/{}", resolve_alias(ns), name)),
+    }
+}
+
+// Records the metadata of a var defined at the top level of a namespace,
+// adding :ns, :name and, unless it has one already, :source
+pub fn set_var_meta(env: &Env, sym: &Value, meta: Value, source: Value) -> MalRet {
+    let (ns, name) = match (&env.ns, sym) {
+        (Some(ns), Symbol(ref s)) => (ns.clone(), s.clone()),
+        _ => return Ok(Null),
+    };
+    let mut hm = match meta.strip() {
+        Map(hm) => hm.clone(),
+        _ => HashMap::new(),
+    };
+    hm.entry(key_string(&keyword("source"))?).or_insert(source);
+    hm.insert(key_string(&keyword("ns"))?, Symbol(ns.clone()));
+    hm.insert(key_string(&keyword("name"))?, sym.clone());
+    let meta = Map(hm);
+    VAR_META.with(|m| {
+        m.borrow_mut()
+            .insert(format!("{}/{}", ns, name), meta.clone())
+    });
+    Ok(meta)
+}
+
+// Returns the metadata of the var with the qualified name
+pub fn var_meta(qualified: &str) -> Option<Value> {
+    VAR_META.with(|m| m.borrow().get(qualified).cloned())
+}
+
+// Returns the qualified names of all the vars, sorted
+pub fn all_vars() -> Vec<String> {
+    let mut names: Vec<String> = NAMESPACES.with(|nss| {
+        nss.borrow()
+            .iter()
+            .flat_map(|(ns, env)| {
+                env.data
+                    .borrow()
+                    .keys()
+                    .map(|k| format!("{}/{}", ns, k))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    names.sort();
+    names
+}
+
+// Returns the qualified name of the dynamic var the symbol, which may be
+// qualified, refers to. Vars are dynamic when defined with ^:dynamic.
+pub fn dynamic_var(env: &Env, sym: &Value) -> Result<String, MalErr> {
+    let s = match sym {
+        Symbol(ref s) => s,
+        _ => return Err(ErrString("binding: expected a symbol".to_string())),
+    };
+    env_get(env, sym)?;
+    let var = resolve_var(env, sym);
+    let dynamic = var
+        .as_ref()
+        .and_then(|v| var_meta(v))
+        .is_some_and(|meta| match meta {
+            Map(hm) => !matches!(hm.get(":dynamic"), None | Some(Null) | Some(Boolean(false))),
+            _ => false,
+        });
+    if !dynamic {
+        return Err(ErrString(format!(
+            "can't dynamically bind non-dynamic var '{}', declare it with ^:dynamic",
+            s
+        )));
+    }
+    Ok(var.unwrap())
+}
+
+// Returns the value the innermost binding form being evaluated gives the
+// var. *ns* is the namespace current on this thread, like a var every
+// thread binds.
+fn bound_value(ns: &str, name: &str) -> Option<Value> {
+    if name == "*ns*" && ns == CORE_NS {
+        return Some(Symbol(current_ns()));
+    }
+    BINDINGS.with(|b| {
+        let b = b.borrow();
+        if b.is_empty() {
+            return None;
+        }
+        let var = format!("{}/{}", ns, name);
+        b.iter()
+            .rev()
+            .find(|(v, _)| *v == var)
+            .map(|(_, val)| val.clone())
+    })
+}
+
+// The values this thread's binding forms give to dynamic vars
+#[cfg(feature = "threads")]
+pub fn bindings() -> Vec<(String, Value)> {
+    BINDINGS.with(|b| b.borrow().clone())
+}
+
+// Binds the dynamic vars, given by qualified name, to the values, until
+// pop_bindings ends the bindings. They are seen by everything running on
+// this thread meanwhile.
+pub fn push_bindings(binds: Vec<(String, Value)>) {
+    BINDINGS.with(|b| b.borrow_mut().extend(binds));
+}
+
+pub fn pop_bindings(n: usize) {
+    BINDINGS.with(|b| {
+        let mut b = b.borrow_mut();
+        let len = b.len();
+        b.truncate(len - n);
+    });
+}
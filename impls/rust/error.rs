@@ -0,0 +1,89 @@
+use std::fmt::{self, Display};
+
+extern crate thiserror;
+use self::thiserror::Error as ThisError;
+
+use crate::reader::TokenType;
+
+// Where in the source a syntax error happened. The offending line is kept so
+// the error can be shown with a caret under the column.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    pub snippet: String,
+}
+
+impl Location {
+    // Constructs the location of the byte `offset` on `line` of `input`
+    pub fn new(input: &str, line: usize, offset: usize) -> Location {
+        let start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = input[offset..]
+            .find('\n')
+            .map_or(input.len(), |i| offset + i);
+        Location {
+            line,
+            column: input[start..offset].chars().count() + 1,
+            snippet: input[start..end].trim_end_matches('\r').to_string(),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep tabs in the caret line so it lines up with the snippet
+        let padding: String = self
+            .snippet
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(
+            f,
+            "line {}, column {}:\n{}\n{}^",
+            self.line, self.column, self.snippet, padding
+        )
+    }
+}
+
+#[derive(ThisError, Debug, PartialEq, Clone)]
+pub enum Error {
+    #[error("unexpected character '{0}' at {1}")]
+    UnexpectedCharacter(char, Location),
+    #[error("unknown character '{0}' at {1}")]
+    UnknownCharacter(char, Location),
+    #[error("unterminated string starting at {0}")]
+    UnterminatedString(Location),
+    #[error("invalid number '{0}' at {1}")]
+    InvalidNumber(String, Location),
+    #[error("unexpected '{0}' at {1}")]
+    UnexpectedToken(TokenType, Location),
+    #[error("unbalanced '{0}', input ended before it was closed; opened at {1}")]
+    Unbalanced(TokenType, Location),
+    #[error("map literal needs an even number of forms at {0}")]
+    OddMapForms(Location),
+    #[error("#() cannot be nested inside another #() at {0}")]
+    NestedAnonFn(Location),
+    #[error("invalid #inst timestamp {0} at {1}")]
+    InvalidInstant(String, Location),
+    #[error("invalid character literal '{0}' at {1}")]
+    InvalidCharacter(String, Location),
+}
+
+impl Error {
+    // Returns where in the source the error happened
+    pub fn location(&self) -> &Location {
+        match self {
+            Error::UnexpectedCharacter(_, l)
+            | Error::UnknownCharacter(_, l)
+            | Error::UnterminatedString(l)
+            | Error::InvalidNumber(_, l)
+            | Error::UnexpectedToken(_, l)
+            | Error::Unbalanced(_, l)
+            | Error::OddMapForms(l)
+            | Error::NestedAnonFn(l)
+            | Error::InvalidInstant(_, l)
+            | Error::InvalidCharacter(_, l) => l,
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use fnv::{FnvHashMap, FnvHashSet};
+
+use crate::env::{env_clear, env_visit, Env, EnvStruct};
+use crate::sync::{Lock, Shared, Weak};
+use crate::types::{Atom, AtomCell, Channel, Closure, Continuation, Lazy, LazySeq, Value};
+
+// A cycle collector for envs and atoms
+//
+// A closure keeps its env alive, and def! of a recursive function puts the
+// closure back into that env, so the two keep each other alive after the
+// rest of the program dropped them. Atoms can hold themselves the same way.
+// Only envs, atoms, lazy seqs and channels can be changed after they are
+// made, so every cycle goes through one of them. Lazy seqs and channels are
+// only found through the envs and atoms, as the function that makes a lazy
+// seq is a closure, and so are the go blocks that use a channel. Continuations hold
+// the envs and values of the frames they captured, so they are walked like
+// closures.
+//
+// The collector works like the one of CPython. It finds everything reachable
+// from the envs and atoms that are still alive, and subtracts from each
+// strong count the references found inside that graph. What has references
+// left is used from outside, by the Rust stack or the namespaces, and so is
+// everything it reaches. The rest is garbage, and clearing its envs and
+// atoms breaks the cycles so that Rc frees it.
+
+// Collect once this many envs and atoms were made since the last collection
+// left that many alive, and at least this many
+const MIN_THRESHOLD: usize = 1000;
+
+global! {
+    static ENVS: Lock<Vec<Weak<EnvStruct>>> = Lock::new(vec![]);
+    static ATOMS: Lock<Vec<Weak<AtomCell>>> = Lock::new(vec![]);
+    static THRESHOLD: Lock<usize> = Lock::new(MIN_THRESHOLD);
+}
+
+// How many threads besides the main one are running mal code. The collector
+// walks the values of every thread, so it only runs while there are none.
+#[cfg(feature = "threads")]
+static WORKERS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+// Counts a thread running mal code for as long as it lives. The thread
+// starting the worker makes it, so the count is up before the work starts.
+#[cfg(feature = "threads")]
+pub struct Worker(());
+
+#[cfg(feature = "threads")]
+impl Worker {
+    pub fn new() -> Worker {
+        WORKERS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Worker(())
+    }
+}
+
+#[cfg(feature = "threads")]
+impl Drop for Worker {
+    fn drop(&mut self) {
+        WORKERS.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+pub fn track_env(env: &Env) {
+    ENVS.with(|envs| envs.borrow_mut().push(Shared::downgrade(env)));
+}
+
+pub fn track_atom(v: &Value) {
+    if let Value::Atom(Atom(ref cell)) = v {
+        ATOMS.with(|atoms| atoms.borrow_mut().push(Shared::downgrade(cell)));
+    }
+}
+
+// Returns how many of the envs and atoms made so far are alive
+pub fn live_counts() -> (usize, usize) {
+    let envs = ENVS.with(|envs| {
+        envs.borrow()
+            .iter()
+            .filter(|w| w.strong_count() > 0)
+            .count()
+    });
+    let atoms = ATOMS.with(|atoms| {
+        atoms
+            .borrow()
+            .iter()
+            .filter(|w| w.strong_count() > 0)
+            .count()
+    });
+    (envs, atoms)
+}
+
+// The shared parts of values, the nodes of the graph the collector walks.
+// Lists, vectors and maps are held inline, so they are walked as part of
+// the node holding them.
+enum Node {
+    Env(Env),
+    Atom(Shared<AtomCell>),
+    Closure(Shared<Closure>),
+    Cont(Shared<Continuation>),
+    Lazy(Shared<LazySeq>),
+    Chan(Shared<Channel>),
+}
+
+// A reference to a node from inside another one
+enum Edge<'a> {
+    Env(&'a Env),
+    Atom(&'a Shared<AtomCell>),
+    Closure(&'a Shared<Closure>),
+    Cont(&'a Shared<Continuation>),
+    Lazy(&'a Shared<LazySeq>),
+    Chan(&'a Shared<Channel>),
+}
+
+impl Edge<'_> {
+    fn addr(&self) -> usize {
+        match self {
+            Edge::Env(e) => Shared::as_ptr(e) as *const u8 as usize,
+            Edge::Atom(a) => Shared::as_ptr(a) as *const u8 as usize,
+            Edge::Closure(c) => Shared::as_ptr(c) as *const u8 as usize,
+            Edge::Cont(k) => Shared::as_ptr(k) as *const u8 as usize,
+            Edge::Lazy(s) => Shared::as_ptr(s) as *const u8 as usize,
+            Edge::Chan(c) => Shared::as_ptr(c) as *const u8 as usize,
+        }
+    }
+
+    fn to_node(&self) -> Node {
+        match self {
+            Edge::Env(e) => Node::Env((*e).clone()),
+            Edge::Atom(a) => Node::Atom((*a).clone()),
+            Edge::Closure(c) => Node::Closure((*c).clone()),
+            Edge::Cont(k) => Node::Cont((*k).clone()),
+            Edge::Lazy(s) => Node::Lazy((*s).clone()),
+            Edge::Chan(c) => Node::Chan((*c).clone()),
+        }
+    }
+}
+
+// Calls f with the shared parts held by the value, looking inside the
+// collections it holds inline
+fn edges(v: &Value, f: &mut dyn FnMut(Edge)) {
+    match v {
+        Value::List(l) => l.iter().for_each(|v| edges(v, f)),
+        Value::Vec(v) => v.iter().for_each(|v| edges(v, f)),
+        Value::Map(hm) => hm.values().for_each(|v| edges(v, f)),
+        Value::Meta(v, meta) => {
+            edges(v, f);
+            edges(meta, f);
+        }
+        Value::Func(b) => edges(&b.meta, f),
+        Value::Closure(c) => f(Edge::Closure(c)),
+        Value::Cont(k) => f(Edge::Cont(k)),
+        Value::Lazy(s) => f(Edge::Lazy(s)),
+        Value::Chan(c) => f(Edge::Chan(c)),
+        Value::Atom(Atom(ref cell)) | Value::Ref(Atom(ref cell)) => f(Edge::Atom(cell)),
+        _ => (),
+    }
+}
+
+impl Node {
+    fn edge(&self) -> Edge<'_> {
+        match self {
+            Node::Env(e) => Edge::Env(e),
+            Node::Atom(a) => Edge::Atom(a),
+            Node::Closure(c) => Edge::Closure(c),
+            Node::Cont(k) => Edge::Cont(k),
+            Node::Lazy(s) => Edge::Lazy(s),
+            Node::Chan(c) => Edge::Chan(c),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(e) => Shared::strong_count(e),
+            Node::Atom(a) => Shared::strong_count(a),
+            Node::Closure(c) => Shared::strong_count(c),
+            Node::Cont(k) => Shared::strong_count(k),
+            Node::Lazy(s) => Shared::strong_count(s),
+            Node::Chan(c) => Shared::strong_count(c),
+        }
+    }
+
+    // Calls f with the values held by the node
+    fn values(&self, f: &mut dyn FnMut(&Value)) {
+        match self {
+            Node::Env(e) => env_visit(e, f),
+            Node::Atom(a) => {
+                f(&a.borrow());
+                a.watches.borrow().iter().for_each(|(k, w)| {
+                    f(k);
+                    f(w);
+                });
+                f(&a.validator.borrow());
+            }
+            Node::Closure(c) => {
+                f(&c.params);
+                f(&c.body);
+                f(&c.meta);
+            }
+            Node::Cont(k) => k
+                .frames
+                .iter()
+                .for_each(|frame| frame.visit(f, &mut |_| ())),
+            Node::Lazy(s) => match *s.0.borrow() {
+                Lazy::Thunk(ref thunk) => f(thunk),
+                Lazy::Cons(ref first, ref rest) => {
+                    f(first);
+                    f(rest);
+                }
+                Lazy::Empty => (),
+            },
+            Node::Chan(c) => {
+                let state = c.0.borrow();
+                state.buf.iter().for_each(&mut *f);
+                state.putters.iter().for_each(|(v, _)| f(v));
+                let putters = state.putters.iter().map(|(_, w)| w);
+                for w in state.takers.iter().chain(putters) {
+                    f(&w.k);
+                    f(&w.result);
+                    f(&w.port);
+                }
+            }
+        }
+    }
+
+    fn children(&self, f: &mut dyn FnMut(Edge)) {
+        match self {
+            Node::Env(e) => {
+                if let Some(ref outer) = e.outer {
+                    f(Edge::Env(outer));
+                }
+            }
+            Node::Closure(c) => f(Edge::Env(&c.env)),
+            Node::Cont(k) => k
+                .frames
+                .iter()
+                .for_each(|frame| frame.visit(&mut |_| (), &mut |e| f(Edge::Env(e)))),
+            Node::Atom(_) | Node::Lazy(_) | Node::Chan(_) => (),
+        }
+        self.values(&mut |v| edges(v, f));
+    }
+}
+
+// Finds everything reachable from the envs and atoms still alive, keyed by
+// address, with one clone of each node
+fn graph() -> FnvHashMap<usize, Node> {
+    let mut stack: Vec<Node> = ENVS.with(|envs| {
+        envs.borrow()
+            .iter()
+            .filter_map(|w| w.upgrade())
+            .map(Node::Env)
+            .collect()
+    });
+    stack.extend(ATOMS.with(|atoms| {
+        atoms
+            .borrow()
+            .iter()
+            .filter_map(|w| w.upgrade())
+            .map(Node::Atom)
+            .collect::<Vec<_>>()
+    }));
+
+    let mut nodes: FnvHashMap<usize, Node> = FnvHashMap::default();
+    while let Some(node) = stack.pop() {
+        let addr = node.edge().addr();
+        if nodes.contains_key(&addr) {
+            continue;
+        }
+        node.children(&mut |e| {
+            if !nodes.contains_key(&e.addr()) {
+                stack.push(e.to_node())
+            }
+        });
+        nodes.insert(addr, node);
+    }
+    nodes
+}
+
+// Frees the envs and atoms that only cycles keep alive, and returns how many
+// of them there were
+pub fn collect() -> usize {
+    let nodes = graph();
+
+    // Count the references from outside the graph, not counting our clone
+    let mut refs: FnvHashMap<usize, usize> = nodes
+        .iter()
+        .map(|(addr, node)| (*addr, node.strong_count() - 1))
+        .collect();
+    for node in nodes.values() {
+        node.children(&mut |e| {
+            if let Some(r) = refs.get_mut(&e.addr()) {
+                *r = r.saturating_sub(1);
+            }
+        });
+    }
+
+    let mut reachable = FnvHashSet::default();
+    let mut work: Vec<usize> = refs
+        .iter()
+        .filter(|(_, r)| **r > 0)
+        .map(|(addr, _)| *addr)
+        .collect();
+    while let Some(addr) = work.pop() {
+        if !reachable.insert(addr) {
+            continue;
+        }
+        nodes[&addr].children(&mut |e| {
+            if !reachable.contains(&e.addr()) {
+                work.push(e.addr())
+            }
+        });
+    }
+
+    let mut freed = 0;
+    for (addr, node) in nodes.iter() {
+        if reachable.contains(addr) {
+            continue;
+        }
+        match node {
+            Node::Env(e) => env_clear(e),
+            Node::Atom(a) => {
+                let held = (
+                    a.replace(Value::Null),
+                    a.watches.replace(vec![]),
+                    a.validator.replace(Value::Null),
+                );
+                drop(held);
+            }
+            Node::Lazy(s) => drop(s.0.replace(Lazy::Empty)),
+            Node::Chan(c) => {
+                let mut state = c.0.borrow_mut();
+                let held = (
+                    std::mem::take(&mut state.buf),
+                    std::mem::take(&mut state.takers),
+                    std::mem::take(&mut state.putters),
+                );
+                drop(state);
+                drop(held);
+            }
+            _ => continue,
+        }
+        freed += 1;
+    }
+    drop(nodes);
+
+    ENVS.with(|envs| envs.borrow_mut().retain(|w| w.strong_count() > 0));
+    ATOMS.with(|atoms| atoms.borrow_mut().retain(|w| w.strong_count() > 0));
+    let (envs, atoms) = live_counts();
+    THRESHOLD.with(|t| *t.borrow_mut() = MIN_THRESHOLD.max(2 * (envs + atoms)));
+    freed
+}
+
+// How many values of each kind the program holds, in its envs and atoms and
+// the values they reach. Values only held by the Rust stack are not counted.
+#[derive(Debug, Default)]
+pub struct Census {
+    pub lists: usize,
+    pub vectors: usize,
+    pub maps: usize,
+    pub closures: usize,
+    pub envs: usize,
+    pub atoms: usize,
+}
+
+// Counts the collections the value holds inline, itself included
+fn count_collections(v: &Value, census: &mut Census) {
+    match v {
+        Value::List(l) => {
+            census.lists += 1;
+            l.iter().for_each(|v| count_collections(v, census));
+        }
+        Value::Vec(v) => {
+            census.vectors += 1;
+            v.iter().for_each(|v| count_collections(v, census));
+        }
+        Value::Map(hm) => {
+            census.maps += 1;
+            hm.values().for_each(|v| count_collections(v, census));
+        }
+        Value::Meta(v, meta) => {
+            count_collections(v, census);
+            count_collections(meta, census);
+        }
+        _ => (),
+    }
+}
+
+pub fn census() -> Census {
+    let nodes = graph();
+    let mut census = Census::default();
+    for node in nodes.values() {
+        match node {
+            Node::Env(_) => census.envs += 1,
+            Node::Atom(_) => census.atoms += 1,
+            Node::Closure(_) | Node::Cont(_) => census.closures += 1,
+            Node::Lazy(_) => census.lists += 1,
+            Node::Chan(_) => (),
+        }
+        node.values(&mut |v| count_collections(v, &mut census));
+    }
+    census
+}
+
+// Collects when enough envs and atoms were made since the last collection.
+// Only call it where no env or atom is borrowed.
+pub fn maybe_collect() {
+    #[cfg(feature = "threads")]
+    if WORKERS.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+        return;
+    }
+    let made = ENVS.with(|envs| envs.borrow().len()) + ATOMS.with(|atoms| atoms.borrow().len());
+    if made >= THRESHOLD.with(|t| *t.borrow()) {
+        collect();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::env::{env_new, env_sets};
+    use crate::types::{atom, list, MalRet};
+    use std::sync::{Mutex, MutexGuard};
+
+    // With the threads feature the registries are shared by all the tests,
+    // so the ones that track envs or atoms run one at a time
+    static REGISTRIES: Mutex<()> = Mutex::new(());
+
+    pub(crate) fn lock_registries() -> MutexGuard<'static, ()> {
+        REGISTRIES.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn eval(_ast: Value, _env: Env) -> MalRet {
+        Ok(Value::Null)
+    }
+
+    // What (let* [a (atom nil)] (def! f (fn* [] (f))) (reset! a a)) leaves
+    // behind, returning the env of the let*
+    pub(crate) fn make_cycles(root: &Env) -> Env {
+        let env = env_new(Some(root.clone()));
+        let f = Value::Closure(Shared::new(Closure {
+            eval,
+            params: list(vec![]),
+            body: list(vec![]),
+            env: env.clone(),
+            is_macro: false,
+            meta: Value::Null,
+        }));
+        env_sets(&env, "f", f);
+        let a = atom(&Value::Null);
+        track_atom(&a);
+        if let Value::Atom(Atom(ref cell)) = a {
+            cell.replace(a.clone());
+        }
+        env_sets(&env, "a", a);
+        env
+    }
+
+    #[test]
+    fn test_live_counts_stay_flat() {
+        let _registries = lock_registries();
+        let (envs, atoms) = live_counts();
+        let root = env_new(None);
+        let mut counts = vec![];
+        for _ in 0..5 {
+            for _ in 0..1000 {
+                make_cycles(&root);
+            }
+            assert_eq!(collect(), 2000);
+            counts.push(live_counts());
+        }
+        assert!(counts.iter().all(|c| *c == (envs + 1, atoms)));
+    }
+
+    #[test]
+    fn test_reachable_cycles_are_kept() {
+        let _registries = lock_registries();
+        let (envs, atoms) = live_counts();
+        let root = env_new(None);
+        let kept = make_cycles(&root);
+        make_cycles(&root);
+        assert_eq!(collect(), 2);
+        assert_eq!(live_counts(), (envs + 2, atoms + 1));
+        drop(kept);
+        assert_eq!(collect(), 2);
+        assert_eq!(live_counts(), (envs + 1, atoms));
+    }
+}
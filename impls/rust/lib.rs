@@ -0,0 +1,133 @@
+// mal as a library, for programs that evaluate mal code they did not write
+//
+// This is the interpreter of stepA_mal without its REPL, see embed below.
+#![allow(dead_code)]
+
+include!("stepA_mal.rs");
+
+// An embedder makes the environment once with init_env, then evaluates each
+// snippet with eval_str under the limits it gives it. Going over one of them
+// ends the evaluation with MalErr::ErrLimit, whatever try* the snippet has.
+pub mod embed {
+    pub use crate::env::Env;
+    pub use crate::init_env;
+    pub use crate::limits::Limits;
+    pub use crate::types::{format_error, MalErr, MalRet, Value};
+
+    use crate::limits::set_limits;
+    use crate::{check_recur, core, eval, read};
+
+    // Evaluates the forms of the string one after the other, with fuel and
+    // time counted from now, and returns the value of the last one. The go
+    // blocks they started run under the same limits.
+    pub fn eval_str(src: &str, env: &Env, limits: Limits) -> MalRet {
+        set_limits(limits);
+        let res = read(&format!("(do {}\n)", src)).and_then(|ast| match ast {
+            Some(ast) => {
+                check_recur(&ast, env, false, false)?;
+                let v = eval(ast, env.clone())?;
+                core::run_scheduler(true)?;
+                Ok(v)
+            }
+            None => Ok(Value::Null),
+        });
+        set_limits(Limits::default());
+        res
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::gc::tests::lock_registries;
+        use std::time::Duration;
+
+        fn eval_limited(src: &str, limits: Limits) -> MalRet {
+            eval_str(src, &init_env(vec![]), limits)
+        }
+
+        fn limit(what: &str) -> MalRet {
+            Err(MalErr::ErrLimit(what.to_string()))
+        }
+
+        #[test]
+        fn test_fuel_ends_loops_try_cannot_catch() {
+            let _registries = lock_registries();
+            let limits = Limits {
+                fuel: Some(10000),
+                ..Limits::default()
+            };
+            assert_eq!(
+                eval_limited(
+                    "(def! spin (fn* () (spin))) (try* (spin) (catch* e :caught))",
+                    limits.clone()
+                ),
+                limit("evaluation fuel exhausted")
+            );
+            // Each evaluation gets the whole budget
+            assert_eq!(eval_limited("(+ 1 2)", limits), Ok(Value::Number(3.0)));
+        }
+
+        #[test]
+        fn test_time_and_depth() {
+            let _registries = lock_registries();
+            let time = Limits {
+                time: Some(Duration::from_millis(50)),
+                ..Limits::default()
+            };
+            assert_eq!(
+                eval_limited("(def! spin2 (fn* () (spin2))) (spin2)", time),
+                limit("evaluation time exceeded")
+            );
+            let depth = Limits {
+                depth: Some(100),
+                ..Limits::default()
+            };
+            assert_eq!(
+                eval_limited(
+                    "(def! deep (fn* (n) (if (= n 0) 0 (+ 1 (deep (- n 1)))))) (deep 1000)",
+                    depth
+                ),
+                limit("recursion depth exceeded")
+            );
+        }
+
+        #[test]
+        fn test_size_is_checked_before_building() {
+            let _registries = lock_registries();
+            let size = Limits {
+                size: Some(10),
+                ..Limits::default()
+            };
+            for src in [
+                "(str \"0123456789\" \"x\")",
+                "(pr-str \"0123456789\")",
+                "(apply list (vec (list 1 2 3 4 5 6 7 8 9 10 11)))",
+            ] {
+                assert_eq!(
+                    eval_limited(src, size.clone()),
+                    limit("allocation size exceeded"),
+                    "{}",
+                    src
+                );
+            }
+            // Source text isn't a collection the program builds, reading it
+            // is only limited by the forms it reads
+            assert_eq!(
+                eval_limited("(count (read-string \"(1 2 3 4 5 6)\"))", size),
+                Ok(Value::Number(6.0))
+            );
+        }
+
+        #[test]
+        fn test_thrown_limit_map_is_caught() {
+            let _registries = lock_registries();
+            assert_eq!(
+                eval_limited(
+                    "(try* (throw {:mal/limit \"x\"}) (catch* e (get e :mal/limit)))",
+                    Limits::default()
+                ),
+                Ok(Value::String("x".to_string()))
+            );
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+use crate::types::MalErr;
+use crate::types::MalErr::ErrLimit;
+
+// Limits on what evaluating untrusted code may use, for embedders running
+// snippets they did not write. Going over one throws an error that try*
+// does not catch, so it ends the evaluation that the embedder started.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    // How many steps eval may take, each form it evaluates in a tail
+    // position loop counts as one
+    pub fuel: Option<u64>,
+    // How long the evaluation may run
+    pub time: Option<Duration>,
+    // How deeply eval may recurse
+    pub depth: Option<usize>,
+    // How many elements a collection, or bytes a string, built by a core
+    // function may have
+    pub size: Option<usize>,
+}
+
+thread_local! {
+    static LIMITS: RefCell<Limits> = RefCell::new(Limits::default());
+    static FUEL_USED: Cell<u64> = const { Cell::new(0) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Sets the limits and starts counting fuel and time from now. Embedders call
+// it again before each evaluation that should get the whole budget.
+pub fn set_limits(limits: Limits) {
+    DEADLINE.with(|d| d.set(limits.time.map(|t| Instant::now() + t)));
+    FUEL_USED.with(|f| f.set(0));
+    LIMITS.with(|current| *current.borrow_mut() = limits);
+}
+
+// The limits of a thread and how much of them it used, which the threads it
+// starts take on so that they cannot get around them
+#[cfg(feature = "threads")]
+#[derive(Clone)]
+pub struct Inherited {
+    limits: Limits,
+    fuel_used: u64,
+    deadline: Option<Instant>,
+}
+
+#[cfg(feature = "threads")]
+pub fn inherited() -> Inherited {
+    Inherited {
+        limits: LIMITS.with(|l| l.borrow().clone()),
+        fuel_used: FUEL_USED.with(|f| f.get()),
+        deadline: DEADLINE.with(|d| d.get()),
+    }
+}
+
+#[cfg(feature = "threads")]
+pub fn inherit(from: Inherited) {
+    let Inherited {
+        limits,
+        fuel_used,
+        deadline,
+    } = from;
+    LIMITS.with(|l| *l.borrow_mut() = limits);
+    FUEL_USED.with(|f| f.set(fuel_used));
+    DEADLINE.with(|d| d.set(deadline));
+}
+
+fn exceeded(what: &str) -> MalErr {
+    ErrLimit(what.to_string())
+}
+
+// Returns which limit the error is for, when it is one of ours
+pub fn limit_exceeded(e: &MalErr) -> Option<String> {
+    match e {
+        ErrLimit(what) => Some(what.to_string()),
+        _ => None,
+    }
+}
+
+// Called by eval for every step, uses up fuel and checks the time
+pub fn tick() -> Result<(), MalErr> {
+    let (fuel, check_time) = LIMITS.with(|l| {
+        let l = l.borrow();
+        (l.fuel, l.time.is_some())
+    });
+    if let Some(fuel) = fuel {
+        let used = FUEL_USED.with(|f| {
+            f.set(f.get() + 1);
+            f.get()
+        });
+        if used > fuel {
+            return Err(exceeded("evaluation fuel exhausted"));
+        }
+    }
+    if check_time
+        && DEADLINE
+            .with(|d| d.get())
+            .is_some_and(|d| Instant::now() > d)
+    {
+        return Err(exceeded("evaluation time exceeded"));
+    }
+    Ok(())
+}
+
+// Leaves one level of eval's recursion when dropped
+pub struct Depth;
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+// Called by eval when it starts, for as long as the Depth lives
+pub fn enter() -> Result<Depth, MalErr> {
+    let depth = DEPTH.with(|d| {
+        d.set(d.get() + 1);
+        d.get()
+    });
+    let guard = Depth;
+    match LIMITS.with(|l| l.borrow().depth) {
+        Some(max) if depth > max => Err(exceeded("recursion depth exceeded")),
+        _ => Ok(guard),
+    }
+}
+
+// Called by eval for each frame it pushes on its stack, which takes one
+// level of recursion until shallower gives it back
+pub fn deeper() -> Result<(), MalErr> {
+    let depth = DEPTH.with(|d| d.get()) + 1;
+    match LIMITS.with(|l| l.borrow().depth) {
+        Some(max) if depth > max => Err(exceeded("recursion depth exceeded")),
+        _ => {
+            DEPTH.with(|d| d.set(depth));
+            Ok(())
+        }
+    }
+}
+
+pub fn shallower(n: usize) {
+    DEPTH.with(|d| d.set(d.get() - n));
+}
+
+// Called by the core functions building collections and strings before they
+// build one of `size` elements or bytes
+pub fn check_size(size: usize) -> Result<(), MalErr> {
+    match LIMITS.with(|l| l.borrow().size) {
+        Some(max) if size > max => Err(exceeded("allocation size exceeded")),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use fnv::FnvHashSet;
+
+use crate::env::{current_ns, in_ns, ns_env, ns_exists, ns_require, require_name, Env};
+use crate::reader::read_all_str;
+use crate::sync::Lock;
+use crate::types::{error, list, MalRet, Value};
+
+// Directories to look for modules in, separated like PATH
+pub const LOAD_PATH_VAR: &str = "MAL_LOAD_PATH";
+
+global! {
+    static LOAD_PATH: Lock<Vec<PathBuf>> = Lock::new(vec![]);
+    // Modules that finished loading, they are never loaded again
+    static LOADED: Lock<FnvHashSet<String>> = Lock::new(FnvHashSet::default());
+}
+
+thread_local! {
+    // Modules being loaded by this thread, outermost first, to report
+    // circular requires
+    static LOADING: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+// Sets the load path to the given directories, then those in MAL_LOAD_PATH,
+// then the current directory
+pub fn init_load_path(dirs: Vec<PathBuf>) {
+    let mut path = dirs;
+    if let Some(var) = env::var_os(LOAD_PATH_VAR) {
+        path.extend(env::split_paths(&var));
+    }
+    path.push(PathBuf::from("."));
+    LOAD_PATH.with(|p| *p.borrow_mut() = path);
+}
+
+// Finds foo.bar as foo/bar.mal in the first directory of the load path that has it
+fn resolve(name: &str) -> Option<PathBuf> {
+    let file: PathBuf = format!("{}.mal", name.replace('.', "/")).into();
+    LOAD_PATH.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|dir| dir.join(&file))
+            .find(|path| path.is_file())
+    })
+}
+
+// Reads the source of the file as one (do ... nil) form, or fails with every
+// syntax error in it, one per line
+fn read_file(path: &Path, src: &str) -> MalRet {
+    match read_all_str(src) {
+        Ok(forms) => {
+            let mut body = vec![Value::Symbol("do".to_string())];
+            body.extend(forms);
+            body.push(Value::Null);
+            Ok(list(body))
+        }
+        Err(errors) => {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|e| format!("{}: {}", path.display(), e))
+                .collect();
+            error(&errors.join("\n"))
+        }
+    }
+}
+
+// Returns the modules from the one given to the innermost one being loaded,
+// when the one given is being loaded
+fn loading_chain(name: &str) -> Option<String> {
+    LOADING.with(|l| {
+        let l = l.borrow();
+        l.iter()
+            .position(|n| n == name)
+            .map(|i| l[i..].join(" -> "))
+    })
+}
+
+// Loads the module unless it was loaded before. Its forms are evaluated at
+// the top level like load-file does, and the namespace that was current
+// before is restored afterwards.
+pub fn load_module(name: &str, eval: fn(Value, Env) -> MalRet) -> MalRet {
+    if LOADED.with(|l| l.borrow().contains(name)) {
+        return Ok(Value::Null);
+    }
+    if let Some(chain) = loading_chain(name) {
+        return error(&format!("circular require: {} -> {}", chain, name));
+    }
+    let path = match resolve(name) {
+        Some(path) => path,
+        None => {
+            return error(&format!(
+                "could not find module '{}' in the load path",
+                name
+            ))
+        }
+    };
+    let src = match fs::read_to_string(&path) {
+        Ok(src) => src,
+        Err(e) => return error(&format!("{}: {}", path.display(), e)),
+    };
+
+    let ns = current_ns();
+    LOADING.with(|l| l.borrow_mut().push(name.to_string()));
+    let res = read_file(&path, &src).and_then(|ast| eval(ast, ns_env(&ns)));
+    LOADING.with(|l| l.borrow_mut().pop());
+    in_ns(&ns);
+    res?;
+
+    LOADED.with(|l| l.borrow_mut().insert(name.to_string()));
+    if !ns_exists(name) {
+        return error(&format!(
+            "{} did not define namespace '{}'",
+            path.display(),
+            name
+        ));
+    }
+    Ok(Value::Null)
+}
+
+// Evaluates the forms of the file at the top level, for load-file. The
+// source text isn't checked against the size limit, only what it builds.
+pub fn load_file(path: &str, eval: fn(Value, Env) -> MalRet) -> MalRet {
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => return error(&e.to_string()),
+    };
+    let ast = read_file(Path::new(path), &src)?;
+    eval(ast, ns_env(&current_ns()))
+}
+
+// Handles one argument of require, loading the module first when its
+// namespace does not exist yet. A module being loaded has its namespace
+// already, but only the part of it before the require that got back to it.
+pub fn require(spec: &Value, eval: fn(Value, Env) -> MalRet) -> MalRet {
+    let name = require_name(spec)?;
+    if loading_chain(&name).is_some() || !ns_exists(&name) {
+        load_module(&name, eval)?;
+    }
+    ns_require(spec)
+}
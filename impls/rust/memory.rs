@@ -0,0 +1,108 @@
+#[cfg(feature = "counting-allocator")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::gc::census;
+use crate::types::{hash_map, keyword, MalArgs, MalRet, Value};
+
+// The system allocator, counting the bytes that go through it. Without the
+// counting-allocator feature it isn't installed and no bytes are counted.
+#[cfg(feature = "counting-allocator")]
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "counting-allocator")]
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        FREED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "counting-allocator")]
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Returns the bytes allocated since the start, and those still in use
+fn bytes() -> (usize, usize) {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    (allocated, allocated - FREED.load(Ordering::Relaxed))
+}
+
+// (mal.sys/memory-stats) gives the live values of each kind the program
+// holds, see gc::census, and the bytes allocated so far and still in use
+pub fn memory_stats(_a: MalArgs) -> MalRet {
+    let c = census();
+    let (allocated, in_use) = bytes();
+    let stats = [
+        ("lists", c.lists),
+        ("vectors", c.vectors),
+        ("maps", c.maps),
+        ("closures", c.closures),
+        ("envs", c.envs),
+        ("atoms", c.atoms),
+        ("bytes-allocated", allocated),
+        ("bytes-in-use", in_use),
+    ];
+    hash_map(
+        stats
+            .iter()
+            .flat_map(|(k, n)| vec![keyword(k), Value::Number(*n as f64)])
+            .collect(),
+    )
+}
+
+// The summary --memory-stats prints at exit
+pub fn summary() -> String {
+    let c = census();
+    let (allocated, in_use) = bytes();
+    format!(
+        "memory: {} lists, {} vectors, {} maps, {} closures, {} envs, {} atoms\n\
+         memory: {} bytes allocated, {} bytes in use",
+        c.lists, c.vectors, c.maps, c.closures, c.envs, c.atoms, allocated, in_use
+    )
+}
+
+#[cfg(all(test, feature = "counting-allocator"))]
+mod tests {
+    use super::*;
+    use crate::env::env_new;
+    use crate::gc::collect;
+    use crate::gc::tests::{lock_registries, make_cycles};
+
+    #[test]
+    fn test_bytes_in_use_stay_flat() {
+        let _registries = lock_registries();
+        let root = env_new(None);
+        let mut in_use = vec![];
+        let mut garbage = 0;
+        for _ in 0..5 {
+            for _ in 0..1000 {
+                make_cycles(&root);
+            }
+            let before = bytes().1;
+            assert_eq!(collect(), 2000);
+            let after = bytes().1;
+            garbage = garbage.max(before.saturating_sub(after));
+            in_use.push(after);
+        }
+        // Each round would add its garbage to the bytes in use if the
+        // collector left it behind. Other tests allocate at the same time,
+        // so allow some noise.
+        assert!(garbage > 0);
+        assert!(in_use[4].saturating_sub(in_use[0]) < garbage / 10);
+    }
+}
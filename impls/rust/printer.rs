@@ -1,52 +1,411 @@
+use std::cell::RefCell;
 use std::fmt::{self, Display};
 
-use crate::types::Value;
+use crate::reader::CHAR_NAMES;
+use crate::sync::Shared;
+use crate::types::{key_value, Atom, Value, KEYWORD_PREFIX};
+
+// Returns the string with quotes, backslashes and newlines escaped so it can be read back
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Writes a character the way the reader expects it
+fn write_char(f: &mut fmt::Formatter, c: char) -> fmt::Result {
+    match CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        Some((name, _)) => write!(f, "\\{}", name),
+        None if c.is_control() => write!(f, "\\u{:04x}", c as u32),
+        None => write!(f, "\\{}", c),
+    }
+}
 
 impl Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Number(x) => write!(f, "{}", x),
-            Value::String(x) => write!(f, "{}", x),
+            Value::String(x) if x.starts_with(KEYWORD_PREFIX) => {
+                write!(f, ":{}", &x[KEYWORD_PREFIX.len()..])
+            }
+            Value::String(x) => write!(f, "\"{}\"", escape(x)),
+            Value::Char(x) => write_char(f, *x),
             Value::Boolean(x) => match x {
                 true => write!(f, "true"),
                 false => write!(f, "false"),
             },
+            Value::Symbol(x) => write!(f, "{}", x),
+            Value::Null => write!(f, "nil"),
+            Value::Error(x) => write!(f, "{}", x),
+            Value::Keyword(x) => write!(f, "{}", x),
+            Value::Func(_) => write!(f, "#<function>"),
+            Value::Closure(c) if c.is_macro => write!(f, "#<macro>"),
+            Value::Closure(_) => write!(f, "#<function>"),
+            Value::Cont(_) => write!(f, "#<continuation>"),
+            Value::Chan(_) => write!(f, "#<channel>"),
+            Value::Ref(_) => write!(f, "#<ref>"),
+            #[cfg(feature = "threads")]
+            Value::Future(_) => write!(f, "#<future>"),
+            // Collections and atoms go through the layout so they honor the print limits
+            Value::List(_)
+            | Value::Vec(_)
+            | Value::Map(_)
+            | Value::Atom(_)
+            | Value::Lazy(_)
+            | Value::Meta(..) => {
+                write!(f, "{}", pr_str(self, true))
+            }
+        }
+    }
+}
+
+// Returns the value printed readably, as pr-str does, or for people to
+// read, as str does, with strings and characters as they are
+pub fn pr_str(v: &Value, readable: bool) -> String {
+    let doc = DocBuilder::new(0, &print_limits(), readable).doc(v, 0);
+    layout(&doc, None)
+}
+
+// Prints the values as pr_str does, between `start` and `end` and separated by `join`
+pub fn pr_seq(values: &[Value], readable: bool, start: &str, end: &str, join: &str) -> String {
+    let strs: Vec<String> = values.iter().map(|v| pr_str(v, readable)).collect();
+    format!("{}{}{}", start, strs.join(join), end)
+}
+
+// Returns the key a map keeps the value under, which is the value printed
+// readably without the print limits
+pub fn map_key(v: &Value) -> String {
+    let doc = DocBuilder::new(0, &PrintLimits::default(), true).doc(v, 0);
+    layout(&doc, None)
+}
+
+// Limits on how much of a value is printed, like Clojure's *print-length*
+// and *print-level*. Whatever is cut off is printed as "...".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrintLimits {
+    // How many elements of a list, vector or map are printed
+    pub length: Option<usize>,
+    // How deeply nested collections and atoms are printed
+    pub level: Option<usize>,
+}
+
+thread_local! {
+    // The limits every printer honors, set from *print-length* and *print-level*
+    static PRINT_LIMITS: RefCell<PrintLimits> = RefCell::new(PrintLimits::default());
+}
+
+pub fn print_limits() -> PrintLimits {
+    PRINT_LIMITS.with(|limits| limits.borrow().clone())
+}
+
+// Called by the evaluator whenever *print-length* or *print-level* change
+#[allow(dead_code)]
+pub fn set_print_limits(limits: PrintLimits) {
+    PRINT_LIMITS.with(|current| *current.borrow_mut() = limits);
+}
+
+// How pretty printed values are laid out
+#[derive(Debug, Clone)]
+pub struct PrettyConfig {
+    // Lines are kept within this many columns where possible
+    pub width: usize,
+    // How far the elements of a broken up list, vector or map are indented
+    pub indent: usize,
+}
+
+impl Default for PrettyConfig {
+    fn default() -> PrettyConfig {
+        PrettyConfig {
+            width: 80,
+            indent: 2,
+        }
+    }
+}
+
+// A document for the pretty printer, after Wadler's "A prettier printer".
+// A group is laid out on one line if it fits, otherwise every line break
+// directly inside it becomes a newline.
+enum Doc {
+    Text(String),
+    // A space, or a newline and indentation when the enclosing group is broken up
+    Line,
+    Nest(usize, Box<Doc>),
+    Concat(Vec<Doc>),
+    Group(Box<Doc>),
+}
+
+// Returns the document for a sequence of elements between `open` and `close`
+fn seq_doc(open: &str, elems: Vec<Doc>, close: &str, indent: usize) -> Doc {
+    let mut body = Vec::with_capacity(elems.len() * 2);
+    for (i, elem) in elems.into_iter().enumerate() {
+        if i > 0 {
+            body.push(Doc::Line);
+        }
+        body.push(elem);
+    }
+    Doc::Group(Box::new(Doc::Concat(vec![
+        Doc::Text(open.to_string()),
+        Doc::Nest(indent, Box::new(Doc::Concat(body))),
+        Doc::Text(close.to_string()),
+    ])))
+}
+
+// Builds the document for a value, applying the print limits
+struct DocBuilder<'a> {
+    indent: usize,
+    limits: &'a PrintLimits,
+    // Whether strings and characters are printed so they read back
+    readable: bool,
+    // The atoms and lazy seqs whose contents are being built, by address, to
+    // catch one that holds itself before it recurses forever
+    building: Vec<usize>,
+}
+
+impl DocBuilder<'_> {
+    fn new(indent: usize, limits: &PrintLimits, readable: bool) -> DocBuilder<'_> {
+        DocBuilder {
+            indent,
+            limits,
+            readable,
+            building: Vec::new(),
+        }
+    }
+
+    // Returns the documents for the elements, eliding any past the print length
+    fn elems<'v>(&mut self, elems: impl Iterator<Item = &'v Value>, level: usize) -> Vec<Doc> {
+        let mut docs = Vec::new();
+        for (i, elem) in elems.enumerate() {
+            if self.limits.length == Some(i) {
+                docs.push(Doc::Text("...".to_string()));
+                break;
+            }
+            docs.push(self.doc(elem, level + 1));
+        }
+        docs
+    }
+
+    fn doc(&mut self, v: &Value, level: usize) -> Doc {
+        let v = v.strip();
+        let nested = matches!(
+            v,
+            Value::List(_) | Value::Vec(_) | Value::Map(_) | Value::Atom(_) | Value::Lazy(_)
+        );
+        if nested && self.limits.level.is_some_and(|max| level >= max) {
+            return Doc::Text("...".to_string());
+        }
+        match v {
             Value::List(x) => {
-                write!(f, "(")?;
-                // Iterate over list except for the last element
-                // to avoid a trailing space
-                for elem in x.iter() {
-                    write!(f, "{}", elem)?;
-                    // Print a space unless on the last element of the list
-                    // TODO do this better
-                    if elem != x.back().unwrap() {
-                        write!(f, " ")?;
-                    }
-                }
-                write!(f, ")")
+                let elems = self.elems(x.iter(), level);
+                seq_doc("(", elems, ")", self.indent)
             }
             Value::Vec(x) => {
-                write!(f, "[")?;
-                for elem in x.iter() {
-                    write!(f, "{}", elem)?;
+                let elems = self.elems(x.iter(), level);
+                seq_doc("[", elems, "]", self.indent)
+            }
+            // Only what has been realized is printed, the printing functions
+            // realize as much as they print first
+            Value::Lazy(s) => {
+                let ptr = Shared::as_ptr(s) as usize;
+                if self.building.contains(&ptr) {
+                    return Doc::Text("#<cycle>".to_string());
+                }
+                let (realized, more) = s.realized(self.limits.length);
+                self.building.push(ptr);
+                let mut elems = self.elems(realized.iter(), level);
+                self.building.pop();
+                if more && elems.len() == realized.len() {
+                    elems.push(Doc::Text("...".to_string()));
                 }
-                write!(f, "]")
+                seq_doc("(", elems, ")", self.indent)
             }
+            // A key stays on the line of its value unless the pair itself does not fit
             Value::Map(x) => {
-                write!(f, "{{")?;
-                for (k, v) in x.iter() {
-                    write!(f, "{}:{}", k, v)?;
+                let mut pairs = Vec::new();
+                for (i, (k, v)) in x.iter().enumerate() {
+                    if self.limits.length == Some(i) {
+                        pairs.push(Doc::Text("...".to_string()));
+                        break;
+                    }
+                    let v = self.doc(v, level + 1);
+                    let k = match self.readable {
+                        true => k.to_string(),
+                        false => self.leaf(&key_value(k)),
+                    };
+                    pairs.push(Doc::Group(Box::new(Doc::Concat(vec![
+                        Doc::Text(k),
+                        Doc::Nest(self.indent, Box::new(Doc::Concat(vec![Doc::Line, v]))),
+                    ]))));
                 }
-                write!(f, "}}")
+                seq_doc("{", pairs, "}", self.indent)
             }
-            Value::Symbol(x) => write!(f, "{}", x),
-            Value::Null => write!(f, "null"),
-            Value::Error(x) => write!(f, "{}", x),
-            Value::Keyword(x) => write!(f, "{}", x),
+            Value::Atom(Atom(cell)) => {
+                let ptr = Shared::as_ptr(cell) as usize;
+                if self.building.contains(&ptr) {
+                    return Doc::Text("#<cycle>".to_string());
+                }
+                self.building.push(ptr);
+                let contents = self.doc(&cell.borrow(), level + 1);
+                self.building.pop();
+                seq_doc(
+                    "(",
+                    vec![Doc::Text("atom".to_string()), contents],
+                    ")",
+                    self.indent,
+                )
+            }
+            _ => Doc::Text(self.leaf(v)),
         }
     }
+
+    fn leaf(&self, v: &Value) -> String {
+        match v {
+            Value::String(s) if !self.readable && !v.keyword_q() => s.to_string(),
+            Value::Char(c) if !self.readable => c.to_string(),
+            _ => v.to_string(),
+        }
+    }
+}
+
+// Returns whether everything up to the next newline fits in `width` columns,
+// when `next` is laid out flat and followed by the documents on `rest`
+fn fits(width: usize, next: &Doc, indent: usize, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut remaining = width as isize;
+    let mut local = vec![(indent, true, next)];
+    let mut rest = rest.iter().rev();
+    while remaining >= 0 {
+        let (i, flat, doc) = match local.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(item) => *item,
+                None => return true,
+            },
+        };
+        match doc {
+            Doc::Text(s) => remaining -= s.chars().count() as isize,
+            Doc::Line if flat => remaining -= 1,
+            Doc::Line => return true,
+            Doc::Nest(j, d) => local.push((i + j, flat, d)),
+            Doc::Concat(docs) => local.extend(docs.iter().rev().map(|d| (i, flat, d))),
+            Doc::Group(d) => local.push((i, flat, d)),
+        }
+    }
+    false
 }
 
+// Lays the document out in `width` columns, or all on one line without a width
+fn layout(doc: &Doc, width: Option<usize>) -> String {
+    let mut out = String::new();
+    let mut column = 0;
+    // Documents still to lay out, with their indentation and whether they are flat
+    let mut stack = vec![(0, width.is_none(), doc)];
+    while let Some((i, flat, doc)) = stack.pop() {
+        match doc {
+            Doc::Text(s) => {
+                out.push_str(s);
+                column += s.chars().count();
+            }
+            Doc::Line if flat => {
+                out.push(' ');
+                column += 1;
+            }
+            Doc::Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(i));
+                column = i;
+            }
+            Doc::Nest(j, d) => stack.push((i + j, flat, d)),
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (i, flat, d))),
+            Doc::Group(d) => {
+                let flat = flat || fits(width.unwrap_or(0).saturating_sub(column), d, i, &stack);
+                stack.push((i, flat, d));
+            }
+        }
+    }
+    out
+}
+
+// Returns the value printed readably, with lists, vectors and maps broken
+// over several lines where they do not fit in the configured width
+pub fn pr_str_pretty(v: &Value, config: &PrettyConfig) -> String {
+    let doc = DocBuilder::new(config.indent, &print_limits(), true).doc(v, 0);
+    layout(&doc, Some(config.width))
+}
+
+// Prints the value as the REPL shows it. Values too long for one line are
+// pretty printed.
 pub fn print_value(v: &Value) {
-    println!("{}", v);
+    println!("{}", pr_str_pretty(v, &PrettyConfig::default()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read;
+
+    fn pretty(input: &str, width: usize, indent: usize) -> String {
+        pr_str_pretty(&read(input).unwrap(), &PrettyConfig { width, indent })
+    }
+
+    #[test]
+    fn test_fits_on_one_line() {
+        let input = "(def square (fn [x] (* x x)))";
+        assert_eq!(pretty(input, 80, 2), input);
+        assert_eq!(pretty(input, input.len(), 2), input);
+    }
+
+    #[test]
+    fn test_breaks_outer_groups_first() {
+        let input = "(def square (fn [x] (* x x)))";
+        assert_eq!(pretty(input, 20, 2), "(def\n  square\n  (fn [x] (* x x)))");
+        assert_eq!(
+            pretty(input, 18, 4),
+            "(def\n    square\n    (fn\n        [x]\n        (* x x)))"
+        );
+    }
+
+    #[test]
+    fn test_closing_delimiters_count() {
+        // "(a bbbb)" fits in 8 columns, but not with the "))" that follows it
+        assert_eq!(pretty("((a bbbb))", 9, 1), "((a\n  bbbb))");
+    }
+
+    #[test]
+    fn test_print_limits() {
+        let v = read("[1 [2 [3 [4]]] (5 6 7 8)]").unwrap();
+        set_print_limits(PrintLimits {
+            length: Some(2),
+            level: None,
+        });
+        assert_eq!(v.to_string(), "[1 [2 [3 [4]]] ...]");
+        set_print_limits(PrintLimits {
+            length: None,
+            level: Some(2),
+        });
+        assert_eq!(v.to_string(), "[1 [2 ...] (5 6 7 8)]");
+        assert_eq!(
+            pr_str_pretty(&v, &PrettyConfig::default()),
+            "[1 [2 ...] (5 6 7 8)]"
+        );
+        set_print_limits(PrintLimits::default());
+        assert_eq!(v.to_string(), "[1 [2 [3 [4]]] (5 6 7 8)]");
+    }
+
+    #[test]
+    fn test_atom_cycle() {
+        let a = Atom::new(Value::Number(1.0));
+        let v = Value::Vec(vec![Value::Atom(a.clone()), Value::Atom(a.clone())]);
+        assert_eq!(v.to_string(), "[(atom 1) (atom 1)]");
+
+        *a.0.borrow_mut() = Value::List(vec![Value::Atom(a.clone())].into());
+        assert_eq!(v.to_string(), "[(atom (#<cycle>)) (atom (#<cycle>))]");
+    }
+
+    #[test]
+    fn test_map_pairs() {
+        assert_eq!(
+            pretty("{\"key\" [1 2 3 4 5 6]}", 12, 2),
+            "{\"key\"\n    [1\n      2\n      3\n      4\n      5\n      6]}"
+        );
+        assert_eq!(pretty("{\"key\" [1 2]}", 14, 2), "{\"key\" [1 2]}");
+    }
 }
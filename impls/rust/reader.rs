@@ -1,4 +1,5 @@
-use crate::error::Error;
+use crate::error::{Error, Location};
+use crate::printer::map_key;
 use crate::types::Value;
 use std::{
     collections::{HashMap, VecDeque},
@@ -6,6 +7,7 @@ use std::{
 };
 
 #[derive(Debug, PartialEq, Clone)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum TokenType {
     // Single character tokens
     LeftParen,
@@ -26,6 +28,18 @@ pub enum TokenType {
     Backtick,
     SingleQuote,
     Slash,
+    Greater,
+    Less,
+    Caret,
+    // Two character tokens
+    GreaterEqual,
+    LessEqual,
+    TildeAt,
+    // Dispatch tokens, which start with '#'
+    Discard,
+    AnonFn,
+    Regex,
+    Tag,
     // Keywords
     Let,
     Fn,
@@ -37,6 +51,13 @@ pub enum TokenType {
     // Literals
     Number,
     String,
+    Char,
+    // A keyword such as :foo
+    Keyword,
+
+    // Trivia, only produced by Lexer::with_trivia
+    Whitespace,
+    Comment,
 
     // Other
     Identifier,
@@ -65,6 +86,14 @@ impl Display for TokenType {
             TokenType::AtSign => write!(f, "@"),
             TokenType::Backtick => write!(f, "`"),
             TokenType::SingleQuote => write!(f, "'"),
+            TokenType::Greater => write!(f, ">"),
+            TokenType::Less => write!(f, "<"),
+            TokenType::GreaterEqual => write!(f, ">="),
+            TokenType::LessEqual => write!(f, "<="),
+            TokenType::Caret => write!(f, "^"),
+            TokenType::TildeAt => write!(f, "~@"),
+            TokenType::Discard => write!(f, "#_"),
+            TokenType::AnonFn => write!(f, "#("),
             TokenType::Let => write!(f, "let"),
             TokenType::Fn => write!(f, "fn"),
             TokenType::Quote => write!(f, "quote"),
@@ -85,32 +114,60 @@ pub struct Token {
     // value: Option<Value>,
 }
 
+impl Token {
+    pub fn token_type(&self) -> &TokenType {
+        &self.token_type
+    }
+
+    // Returns the source text of the token, exactly as written
+    pub fn text<'a>(&self, input: &'a str) -> &'a str {
+        &input[self.start..self.end]
+    }
+
+    // Returns the location of the token, for error reporting
+    pub fn location(&self, input: &str) -> Location {
+        Location::new(input, self.line, self.start)
+    }
+}
+
 impl Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
-struct Lexer<'a> {
+pub struct Lexer<'a> {
     input: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    // Whether whitespace and comments are returned as tokens instead of skipped
+    trivia: bool,
 }
 
 impl<'a> Lexer<'a> {
     // Constructs a new Reader
-    fn new(input: &'a str) -> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
         Lexer {
             input,
             start: 0,
             current: 0,
             line: 1,
+            trivia: false,
+        }
+    }
+
+    // Constructs a Reader that keeps whitespace and comments, so that the
+    // text of its tokens adds up to the whole input
+    pub fn with_trivia(input: &'a str) -> Lexer<'a> {
+        Lexer {
+            trivia: true,
+            ..Lexer::new(input)
         }
     }
 
     // Advances the current position by one character
     fn advance(&mut self) {
-        if let Some(c) = self.input.chars().nth(self.current) {
+        if let Some(c) = self.peek() {
             if c == '\n' {
                 self.line += 1;
             }
@@ -120,28 +177,27 @@ impl<'a> Lexer<'a> {
 
     // Returns the next character without moving the cursor
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.current)
-    }
-
-    // Returns the character after the next character without moving the cursor
-    fn peek_next(&self) -> Option<char> {
-        self.input.chars().nth(self.current + 1)
+        self.input[self.current..].chars().next()
     }
 
     // Returns the next character and moves the cursor
     fn next_char(&mut self) -> Option<char> {
-        let c = self.input.chars().nth(self.current);
+        let c = self.peek();
         self.advance();
         c
     }
 
     // Returns the next token, skipping whitespace, and comments which are started with ';'
-    fn next(&mut self) -> Result<Token, Error> {
+    pub fn next(&mut self) -> Result<Token, Error> {
+        if !self.trivia {
+            self.skip_whitespace();
+        } else if let Some(token) = self.trivia_token() {
+            return Ok(token);
+        }
         if self.is_at_end() {
             return Ok(self.create_token(TokenType::EOF));
         }
 
-        self.skip_whitespace();
         match self.next_char() {
             Some(c) => match c {
                 '(' => Ok(self.create_token(TokenType::LeftParen)),
@@ -152,34 +208,155 @@ impl<'a> Lexer<'a> {
                 ']' => Ok(self.create_token(TokenType::RightBracket)),
                 ',' => Ok(self.create_token(TokenType::Comma)),
                 '.' => Ok(self.create_token(TokenType::Dot)),
-                '-' => Ok(self.create_token(TokenType::Minus)),
-                '+' => Ok(self.create_token(TokenType::Plus)),
+                '-' if self.peek().is_some_and(|c| c.is_ascii_digit()) => Ok(self.number()),
+                '-' => self.operator(TokenType::Minus),
+                '+' => self.operator(TokenType::Plus),
                 '\'' => Ok(self.create_token(TokenType::SingleQuote)),
-                '*' => Ok(self.create_token(TokenType::Star)),
-                '=' => Ok(self.create_token(TokenType::Equal)),
+                '*' => self.operator(TokenType::Star),
+                '/' => Ok(self.create_token(TokenType::Slash)),
+                '=' => self.operator(TokenType::Equal),
+                '~' if self.peek() == Some('@') => {
+                    self.advance();
+                    Ok(self.create_token(TokenType::TildeAt))
+                }
+                '~' => Ok(self.create_token(TokenType::Tilde)),
                 '@' => Ok(self.create_token(TokenType::AtSign)),
+                '`' => Ok(self.create_token(TokenType::Backtick)),
+                '^' => Ok(self.create_token(TokenType::Caret)),
+                '>' if self.peek() == Some('=') => {
+                    Ok(self.with_equal(TokenType::Greater, TokenType::GreaterEqual))
+                }
+                '<' if self.peek() == Some('=') => {
+                    Ok(self.with_equal(TokenType::Less, TokenType::LessEqual))
+                }
+                '>' => self.operator(TokenType::Greater),
+                '<' => self.operator(TokenType::Less),
+                ':' => self.keyword(),
+                '&' | '_' => self.indentifier_or_keyword(),
                 '"' => self.create_string(),
-                ';' => Err(Error::UnexpectedCharacter(self.create_error())),
+                '#' => self.dispatch(),
+                '%' => Ok(self.anon_fn_arg()),
+                '\\' => self.character(),
+                ';' => Err(Error::UnexpectedCharacter(c, self.location())),
                 _ => {
-                    if c.is_digit(10) {
+                    if c.is_ascii_digit() {
                         Ok(self.number())
                     } else if c.is_alphabetic() {
                         self.indentifier_or_keyword()
                     } else {
-                        Err(Error::UnknownCharacter(self.create_error()))
+                        Err(Error::UnknownCharacter(c, self.location()))
                     }
                 }
             },
 
-            None => return Ok(self.create_token(TokenType::EOF)),
+            None => Ok(self.create_token(TokenType::EOF)),
         }
     }
 
-    fn create_string(&mut self) -> Result<Token, Error> {
+    // Returns the two character token if the next character is '=', otherwise the single one
+    fn with_equal(&mut self, single: TokenType, double: TokenType) -> Token {
+        if self.peek() == Some('=') {
+            self.advance();
+            self.create_token(double)
+        } else {
+            self.create_token(single)
+        }
+    }
+
+    // Returns the operator token, unless the operator starts a symbol such as
+    // -> or *ns*, which it does when a letter or one of !?*> follows it
+    fn operator(&mut self, single: TokenType) -> Result<Token, Error> {
+        match self.peek() {
+            Some(c) if c.is_alphabetic() || "!?*>".contains(c) => self.indentifier_or_keyword(),
+            _ => Ok(self.create_token(single)),
+        }
+    }
+
+    // Returns a keyword token such as :foo or :mal/ref
+    fn keyword(&mut self) -> Result<Token, Error> {
+        let start = self.current;
+        self.symbol_chars();
+        if self.current == start {
+            return Err(Error::UnexpectedCharacter(':', self.location()));
+        }
+        Ok(self.create_token(TokenType::Keyword))
+    }
+
+    // Advances over the characters that continue a symbol. '.' and '/' only
+    // continue a symbol, so namespaced symbols such as foo.bar/baz read as
+    // one while a lone / is still division.
+    fn symbol_chars(&mut self) {
         while let Some(c) = self.peek() {
-            if self.is_at_end() {
-                return Err(Error::UnterminatedString(self.create_error()));
+            if !c.is_alphanumeric() && !"./-_!?*+<>=&#".contains(c) {
+                break;
             }
+            self.advance();
+        }
+    }
+
+    // Returns the token for the dispatch macro following a '#'
+    fn dispatch(&mut self) -> Result<Token, Error> {
+        match self.next_char() {
+            Some('_') => Ok(self.create_token(TokenType::Discard)),
+            Some('(') => Ok(self.create_token(TokenType::AnonFn)),
+            Some('"') => {
+                let mut token = self.create_string()?;
+                token.token_type = TokenType::Regex;
+                Ok(token)
+            }
+            Some(c) if c.is_alphabetic() => {
+                while let Some(c) = self.peek() {
+                    if !c.is_alphanumeric() && !"/.-_".contains(c) {
+                        break;
+                    }
+                    self.advance();
+                }
+                Ok(self.create_token(TokenType::Tag))
+            }
+            Some(c) => Err(Error::UnexpectedCharacter(c, self.location())),
+            None => Err(Error::UnexpectedCharacter('#', self.location())),
+        }
+    }
+
+    // Returns an argument of an anonymous function: %, %&, or % and a number
+    fn anon_fn_arg(&mut self) -> Token {
+        if self.peek() == Some('&') {
+            self.advance();
+        } else {
+            while let Some(c) = self.peek() {
+                if !c.is_ascii_digit() {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.create_token(TokenType::Identifier)
+    }
+
+    // Returns a character literal: \c, a name such as \newline, or \uXXXX
+    fn character(&mut self) -> Result<Token, Error> {
+        if let Some(c) = self.next_char() {
+            if c.is_alphanumeric() {
+                while let Some(c) = self.peek() {
+                    if !c.is_alphanumeric() {
+                        break;
+                    }
+                    self.advance();
+                }
+            }
+        }
+        let token = self.create_token(TokenType::Char);
+        let text = token.text(self.input);
+        match char_value(&text[1..]) {
+            Some(_) => Ok(token),
+            None => Err(Error::InvalidCharacter(text.to_string(), self.location())),
+        }
+    }
+
+    fn create_string(&mut self) -> Result<Token, Error> {
+        // Strings may span lines, remember the one the string started on
+        let line = self.line;
+        while let Some(c) = self.peek() {
             // Check for escaped quotes
             if c == '\\' {
                 self.advance();
@@ -194,6 +371,11 @@ impl<'a> Lexer<'a> {
             }
             self.advance();
         }
+        if self.is_at_end() {
+            return Err(Error::UnterminatedString(Location::new(
+                self.input, line, self.start,
+            )));
+        }
 
         // The closing quote
         self.advance();
@@ -201,7 +383,7 @@ impl<'a> Lexer<'a> {
             token_type: TokenType::String,
             start: self.start,
             end: self.current,
-            line: self.line,
+            line,
             // value: Some(Value::String(
             //     self.input[self.start + 1..self.current - 1].to_string(),
             // )),
@@ -210,12 +392,7 @@ impl<'a> Lexer<'a> {
 
     // This function takes a starting character and a keyword to advance through the character stream to see if it an exact match. If the keyword is not a match, then the identifier is returned instead.
     fn indentifier_or_keyword(&mut self) -> Result<Token, Error> {
-        while let Some(c) = self.peek() {
-            if !c.is_alphanumeric() {
-                break;
-            }
-            self.advance();
-        }
+        self.symbol_chars();
 
         match &self.input[self.start..self.current] {
             "let" => Ok(self.create_symbol(TokenType::Let)),
@@ -228,14 +405,35 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    // Returns a whitespace or comment token if one starts at the cursor
+    fn trivia_token(&mut self) -> Option<Token> {
+        self.start = self.current;
+        let token_type = match self.peek()? {
+            ';' => TokenType::Comment,
+            c if c.is_whitespace() => TokenType::Whitespace,
+            _ => return None,
+        };
+        while let Some(c) = self.peek() {
+            let more = match token_type {
+                // The newline ending a comment is whitespace of its own
+                TokenType::Comment => c != '\n',
+                _ => c.is_whitespace(),
+            };
+            if !more {
+                break;
+            }
+            self.advance();
+        }
+        Some(self.create_token(token_type))
+    }
+
     fn skip_comment(&mut self) {
         while let Some(c) = self.peek() {
+            // advance() counts the newline
+            self.advance();
             if c == '\n' {
-                self.advance();
-                self.line += 1;
                 break;
             }
-            self.advance();
         }
     }
 
@@ -247,11 +445,10 @@ impl<'a> Lexer<'a> {
             }
             self.advance();
         }
-        let mut token = self.create_token(TokenType::Number);
         // token.value = Some(Value::Number(
         //     self.input[self.start..self.current].parse().unwrap(),
         // ));
-        token
+        self.create_token(TokenType::Number)
     }
 
     // Creates a token at the current position
@@ -265,16 +462,9 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn create_error(&self) -> Token {
-        Token {
-            token_type: TokenType::Error,
-            start: self.start,
-            end: self.current,
-            line: self.line,
-            // value: Some(Value::Symbol(
-            //     self.input[self.start..self.current].to_string(),
-            // )),
-        }
+    // Returns the location of the token being scanned, for error reporting
+    fn location(&self) -> Location {
+        Location::new(self.input, self.line, self.start)
     }
 
     // Creates a symbol token at the current position
@@ -293,11 +483,7 @@ impl<'a> Lexer<'a> {
     // Skips all whitespace characters and sets the current position
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
-            if c == '\n' {
-                self.line += 1;
-                self.advance();
-                continue;
-            } else if c.is_whitespace() {
+            if c.is_whitespace() {
                 self.advance();
                 continue;
             }
@@ -306,9 +492,9 @@ impl<'a> Lexer<'a> {
                 self.skip_comment();
                 continue;
             }
-            self.start = self.current;
             break;
         }
+        self.start = self.current;
     }
 
     // Returns whether the end of the input has been reached
@@ -317,27 +503,66 @@ impl<'a> Lexer<'a> {
     }
 }
 
-fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+// Splits the input into tokens, always ending with an EOF token. When `errors`
+// is given, lexical errors are collected there and the offending characters
+// are skipped instead of aborting.
+fn scan(input: &str, mut errors: Option<&mut Vec<Error>>) -> Result<Vec<Token>, Error> {
     let mut lexer = Lexer::new(input);
     let mut tokens = Vec::new();
-    while !lexer.is_at_end() {
-        tokens.push(lexer.next()?);
+    loop {
+        match lexer.next() {
+            Ok(token) => {
+                let done = token.token_type == TokenType::EOF;
+                tokens.push(token);
+                if done {
+                    return Ok(tokens);
+                }
+            }
+            Err(e) => match errors {
+                // Leave an error token behind so the parser knows where the bad input was
+                Some(ref mut errors) => {
+                    errors.push(e);
+                    tokens.push(lexer.create_token(TokenType::Error));
+                }
+                None => return Err(e),
+            },
+        }
     }
-    Ok(tokens)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, Error> {
+    scan(input, None)
 }
 
 struct Parser<'a> {
     tokens: Vec<Token>,
     index: usize,
     input: &'a str,
+    // Only set in recovery mode, where syntax errors are collected here
+    // and parsing carries on with the next form
+    errors: Option<Vec<Error>>,
+    // Whether the parser is inside a #(...), which cannot be nested
+    in_anon_fn: bool,
 }
 
 impl Parser<'_> {
-    fn new(tokens: Vec<Token>, input: &str) -> Parser {
+    fn new(tokens: Vec<Token>, input: &str) -> Parser<'_> {
         Parser {
             tokens,
             index: 0,
             input,
+            errors: None,
+            in_anon_fn: false,
+        }
+    }
+
+    fn recovering(tokens: Vec<Token>, input: &str, errors: Vec<Error>) -> Parser<'_> {
+        Parser {
+            tokens,
+            index: 0,
+            input,
+            errors: Some(errors),
+            in_anon_fn: false,
         }
     }
 
@@ -348,45 +573,216 @@ impl Parser<'_> {
                     self.advance();
                     self.parse_list()
                 }
+                TokenType::LeftBracket => {
+                    self.advance();
+                    self.parse_vec()
+                }
+                TokenType::LeftBrace => {
+                    self.advance();
+                    self.parse_map()
+                }
+                TokenType::AnonFn => {
+                    self.advance();
+                    self.parse_anon_fn()
+                }
+                TokenType::Tag => {
+                    self.advance();
+                    self.parse_tagged()
+                }
+                TokenType::Discard => {
+                    self.skip_discarded()?;
+                    self.parse_form()
+                }
+                TokenType::Comma => {
+                    self.advance();
+                    self.parse_form()
+                }
+                TokenType::SingleQuote => self.parse_prefixed("quote"),
+                TokenType::Backtick => self.parse_prefixed("quasiquote"),
+                TokenType::Tilde => self.parse_prefixed("unquote"),
+                TokenType::TildeAt => self.parse_prefixed("splice-unquote"),
+                TokenType::AtSign => self.parse_prefixed("deref"),
+                TokenType::Caret => {
+                    let meta = self.parse_prefixed("with-meta")?;
+                    let form = self.parse_operand()?;
+                    Ok(match meta {
+                        Value::List(mut l) => {
+                            let meta = l.pop_back().unwrap_or(Value::Null);
+                            l.push_back(form);
+                            l.push_back(meta);
+                            Value::List(l)
+                        }
+                        meta => meta,
+                    })
+                }
+                TokenType::EOF => Ok(Value::Error("End of Tokens".to_string())),
                 _ => {
                     let res = self.parse_atom();
                     self.advance();
                     res
                 }
             },
-            None => return Ok(Value::Error("End of Tokens".to_string())),
+            None => Ok(Value::Error("End of Tokens".to_string())),
         }
     }
 
+    // Parses forms up to the closing token, the opening one having been consumed
+    fn parse_seq(&mut self, close: TokenType) -> Result<VecDeque<Value>, Error> {
+        let open = self.tokens[self.index - 1].clone();
+        let mut forms = VecDeque::new();
+        while let Some(token) = self.peek().cloned() {
+            match token.token_type {
+                ref t if *t == close => {
+                    self.advance();
+                    return Ok(forms);
+                }
+                TokenType::EOF => break,
+                TokenType::Discard => self.skip_discarded()?,
+                TokenType::Comma => self.advance(),
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    let e = self.unexpected(&token);
+                    self.advance();
+                    self.report(e)?;
+                }
+                _ => forms.push_back(self.parse_form()?),
+            }
+        }
+        let e = Error::Unbalanced(open.token_type.clone(), self.location(&open));
+        self.report(e)?;
+        Ok(forms)
+    }
+
     fn parse_list(&mut self) -> Result<Value, Error> {
-        let mut list: VecDeque<Value> = VecDeque::new();
-        while let Some(token) = self.peek() {
-            if token.token_type == TokenType::RightParen {
-                self.advance();
-                break;
+        Ok(Value::List(self.parse_seq(TokenType::RightParen)?))
+    }
+
+    fn parse_vec(&mut self) -> Result<Value, Error> {
+        Ok(Value::Vec(self.parse_seq(TokenType::RightBracket)?.into()))
+    }
+
+    fn parse_map(&mut self) -> Result<Value, Error> {
+        let open = self.tokens[self.index - 1].clone();
+        let forms = self.parse_seq(TokenType::RightBrace)?;
+        if forms.len() % 2 != 0 {
+            let e = Error::OddMapForms(self.location(&open));
+            self.report(e)?;
+        }
+        let mut map = HashMap::new();
+        let mut forms = forms.into_iter();
+        while let (Some(k), Some(v)) = (forms.next(), forms.next()) {
+            map.insert(map_key(&k), v);
+        }
+        Ok(Value::Map(map))
+    }
+
+    fn parse_anon_fn(&mut self) -> Result<Value, Error> {
+        if self.in_anon_fn {
+            let open = self.tokens[self.index - 1].clone();
+            let e = Error::NestedAnonFn(self.location(&open));
+            self.report(e)?;
+        }
+        let outer = self.in_anon_fn;
+        self.in_anon_fn = true;
+        let body = self.parse_seq(TokenType::RightParen);
+        self.in_anon_fn = outer;
+        Ok(anon_fn(body?))
+    }
+
+    fn parse_tagged(&mut self) -> Result<Value, Error> {
+        let tag = self.tokens[self.index - 1].clone();
+        let form = self.parse_form()?;
+        match tagged_literal(&tag.text(self.input)[1..], form) {
+            Ok(value) => Ok(value),
+            Err(text) => {
+                let e = Error::InvalidInstant(text, self.location(&tag));
+                self.report(e)?;
+                Ok(Value::Error("invalid #inst".to_string()))
+            }
+        }
+    }
+
+    // Reads 'x, `x, ~x, ~@x and @x as (name x), and the meta of ^meta x as
+    // (with-meta meta), the caller adding x before the meta
+    fn parse_prefixed(&mut self, name: &str) -> Result<Value, Error> {
+        self.advance();
+        let form = self.parse_operand()?;
+        Ok(Value::List(VecDeque::from(vec![
+            Value::Symbol(name.to_string()),
+            form,
+        ])))
+    }
+
+    // Reads the form a reader macro applies to, which has to be there
+    fn parse_operand(&mut self) -> Result<Value, Error> {
+        match self.peek().cloned() {
+            Some(token)
+                if matches!(
+                    token.token_type,
+                    TokenType::EOF
+                        | TokenType::RightParen
+                        | TokenType::RightBracket
+                        | TokenType::RightBrace
+                ) =>
+            {
+                let prefix = self.tokens[self.index - 1].clone();
+                let e = Error::Unbalanced(prefix.token_type.clone(), self.location(&prefix));
+                self.report(e)?;
+                Ok(Value::Null)
             }
-            list.push_back(self.parse_form()?);
+            _ => self.parse_form(),
         }
-        Ok(Value::List(list))
+    }
+
+    // Skips a #_ and the form after it
+    fn skip_discarded(&mut self) -> Result<(), Error> {
+        self.advance();
+        self.parse_form()?;
+        Ok(())
     }
 
     fn parse_atom(&mut self) -> Result<Value, Error> {
-        match self.peek() {
+        match self.peek().cloned() {
             Some(token) => match token.token_type {
-                TokenType::Number => Ok(Value::Number(
-                    token_to_string(token, self.input)
-                        .parse()
-                        .expect(format!("{} is not a number", token).as_str()),
-                )),
-                TokenType::String => Ok(Value::String(token_to_string(token, self.input))),
-                TokenType::Identifier => Ok(Value::Symbol(token_to_string(token, self.input))),
-                // TODO explicitly handle keywords
-                _ => Ok(Value::Keyword(token.token_type.clone())),
+                TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                    let e = self.unexpected(&token);
+                    self.report(e)?;
+                    Ok(Value::Error(format!("unexpected '{}'", token.token_type)))
+                }
+                // Already reported by the lexer
+                TokenType::Error => Ok(Value::Error(token_to_string(&token, self.input))),
+                _ => match atom_value(&token.token_type, token.text(self.input)) {
+                    Some(value) => Ok(value),
+                    None => {
+                        let text = token_to_string(&token, self.input);
+                        let e = Error::InvalidNumber(text, self.location(&token));
+                        self.report(e)?;
+                        Ok(Value::Error("invalid number".to_string()))
+                    }
+                },
             },
             None => Ok(Value::Null),
         }
     }
 
+    // Hands the error back in strict mode, or records it when recovering
+    fn report(&mut self, error: Error) -> Result<(), Error> {
+        match self.errors {
+            Some(ref mut errors) => {
+                errors.push(error);
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    fn unexpected(&self, token: &Token) -> Error {
+        Error::UnexpectedToken(token.token_type.clone(), self.location(token))
+    }
+
+    fn location(&self, token: &Token) -> Location {
+        token.location(self.input)
+    }
+
     fn peek(&self) -> Option<&Token> {
         self.tokens.get(self.index)
     }
@@ -395,13 +791,265 @@ impl Parser<'_> {
         self.index += 1;
     }
 
+    fn at_end(&self) -> bool {
+        self.peek()
+            .is_none_or(|token| token.token_type == TokenType::EOF)
+    }
+
     pub fn parse(&mut self) -> Result<Value, Error> {
         self.parse_form()
     }
+
+    // Parses every top level form in the input
+    pub fn parse_all(&mut self) -> Result<Vec<Value>, Error> {
+        let mut forms = Vec::new();
+        while !self.at_end() {
+            match self.peek() {
+                Some(token) if token.token_type == TokenType::Discard => self.skip_discarded()?,
+                Some(token) if token.token_type == TokenType::Comma => self.advance(),
+                _ => {
+                    // When recovering, the forms that had errors are left out
+                    let reported = self.errors.as_ref().map_or(0, Vec::len);
+                    let form = self.parse_form()?;
+                    if self.errors.as_ref().map_or(0, Vec::len) == reported
+                        && !has_placeholder(&form)
+                    {
+                        forms.push(form);
+                    }
+                }
+            }
+        }
+        Ok(forms)
+    }
 }
 
+// Converts the source text of a single token to the value it stands for.
+// Returns None if the text of a number token does not parse.
+pub fn atom_value(token_type: &TokenType, text: &str) -> Option<Value> {
+    match token_type {
+        TokenType::Number => text.parse().ok().map(Value::Number),
+        TokenType::String => Some(Value::String(unescape(&text[1..text.len() - 1]))),
+        TokenType::Char => char_value(&text[1..]).map(Value::Char),
+        TokenType::Keyword => Some(Value::String(format!("\u{29e}{}", &text[1..]))),
+        // Regexes keep their escapes, they are for the regex engine to interpret
+        TokenType::Regex => Some(Value::List(VecDeque::from(vec![
+            Value::Symbol("re-pattern".to_string()),
+            Value::String(text[2..text.len() - 1].to_string()),
+        ]))),
+        TokenType::Identifier => Some(match text {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            "nil" => Value::Null,
+            s => Value::Symbol(s.to_string()),
+        }),
+        TokenType::Let
+        | TokenType::Fn
+        | TokenType::Quote
+        | TokenType::Quasiquote
+        | TokenType::Unquote
+        | TokenType::UnquoteSplicing => Some(Value::Symbol(text.to_string())),
+        _ => Some(Value::Keyword(token_type.clone())),
+    }
+}
+
+// The characters with names, as in \newline
+pub const CHAR_NAMES: [(&str, char); 6] = [
+    ("newline", '\n'),
+    ("space", ' '),
+    ("tab", '\t'),
+    ("return", '\r'),
+    ("backspace", '\u{8}'),
+    ("formfeed", '\u{c}'),
+];
+
+// Returns the character a literal stands for, given its text after the backslash
+fn char_value(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        (Some('u'), Some(_)) if text.len() == 5 => u32::from_str_radix(&text[1..], 16)
+            .ok()
+            .and_then(char::from_u32),
+        _ => CHAR_NAMES
+            .iter()
+            .find(|(name, _)| *name == text)
+            .map(|(_, c)| *c),
+    }
+}
+
+// Expands the body of #(...) into a fn* taking the %, %1, %2... and %& used in
+// it as parameters. A bare % is the same as %1.
+pub fn anon_fn(body: VecDeque<Value>) -> Value {
+    let mut arity = 0;
+    let mut rest = false;
+    let body = anon_fn_args(Value::List(body), &mut arity, &mut rest);
+    let mut params: Vec<Value> = (1..=arity)
+        .map(|i| Value::Symbol(format!("%{}", i)))
+        .collect();
+    if rest {
+        params.push(Value::Symbol("&".to_string()));
+        params.push(Value::Symbol("%&".to_string()));
+    }
+    Value::List(VecDeque::from(vec![
+        Value::Symbol("fn*".to_string()),
+        Value::Vec(params),
+        body,
+    ]))
+}
+
+// Renames % to %1 and records the highest numbered argument and whether %& is used
+fn anon_fn_args(form: Value, arity: &mut usize, rest: &mut bool) -> Value {
+    match form {
+        Value::Symbol(ref s) if s == "%" => {
+            *arity = (*arity).max(1);
+            Value::Symbol("%1".to_string())
+        }
+        Value::Symbol(ref s) if s == "%&" => {
+            *rest = true;
+            form
+        }
+        Value::Symbol(ref s) if s.starts_with('%') => {
+            if let Ok(n) = s[1..].parse() {
+                *arity = (*arity).max(n);
+            }
+            form
+        }
+        Value::List(l) => Value::List(
+            l.into_iter()
+                .map(|f| anon_fn_args(f, arity, rest))
+                .collect(),
+        ),
+        Value::Vec(v) => Value::Vec(
+            v.into_iter()
+                .map(|f| anon_fn_args(f, arity, rest))
+                .collect(),
+        ),
+        Value::Map(m) => Value::Map(
+            m.into_iter()
+                .map(|(k, v)| (k, anon_fn_args(v, arity, rest)))
+                .collect(),
+        ),
+        _ => form,
+    }
+}
+
+// Expands a tagged literal. #inst timestamps are read as milliseconds since
+// the epoch, like time-ms returns. Any other tag becomes a call to
+// tagged-literal, which looks the constructor for the tag up in
+// *data-readers* at evaluation time. Returns the text of the form if an
+// #inst is not a valid timestamp.
+pub fn tagged_literal(tag: &str, form: Value) -> Result<Value, String> {
+    match (tag, form) {
+        ("inst", Value::String(s)) => parse_instant(&s).map(Value::Number).ok_or(s),
+        ("inst", form) => Err(form.to_string()),
+        (tag, form) => Ok(Value::List(VecDeque::from(vec![
+            Value::Symbol("tagged-literal".to_string()),
+            Value::String(tag.to_string()),
+            Value::List(VecDeque::from(vec![
+                Value::Symbol("quote".to_string()),
+                form,
+            ])),
+        ]))),
+    }
+}
+
+// Parses an RFC 3339 timestamp such as 2023-11-17T08:30:00.250Z into
+// milliseconds since the epoch. The time and offset may be left out.
+fn parse_instant(s: &str) -> Option<f64> {
+    fn field(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+        let digits = s.get(range)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+    let (year, month, day) = (field(s, 0..4)?, field(s, 5..7)?, field(s, 8..10)?);
+    if s.get(4..5) != Some("-") || s.get(7..8) != Some("-") {
+        return None;
+    }
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut rest = &s[10..];
+    let mut ms = 0;
+    if let Some(time) = rest.strip_prefix('T') {
+        let (hour, minute) = (field(time, 0..2)?, field(time, 3..5)?);
+        if time.get(2..3) != Some(":") || hour > 23 || minute > 59 {
+            return None;
+        }
+        ms += (hour * 60 + minute) * 60_000;
+        rest = &time[5..];
+        if let Some(secs) = rest.strip_prefix(':') {
+            let second = field(secs, 0..2)?;
+            if second > 60 {
+                return None;
+            }
+            ms += second * 1000;
+            rest = &secs[2..];
+            if let Some(frac) = rest.strip_prefix('.') {
+                let len = frac
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(frac.len());
+                if len == 0 {
+                    return None;
+                }
+                // Only milliseconds are kept
+                let digits = format!("{:0<3}", &frac[..len.min(3)]);
+                ms += digits.parse::<i64>().ok()?;
+                rest = &frac[len..];
+            }
+        }
+    }
+    let offset = match rest {
+        "" | "Z" => 0,
+        _ => {
+            let sign = match rest.get(0..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let (hours, minutes) = (field(rest, 1..3)?, field(rest, 4..6)?);
+            if rest.len() != 6 || rest.get(3..4) != Some(":") {
+                return None;
+            }
+            sign * (hours * 60 + minutes) * 60_000
+        }
+    };
+    // Days from 1970-01-01 in the proleptic Gregorian calendar
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some((days * 86_400_000 + ms - offset) as f64)
+}
+
+// Returns the source text of a token. Strings are returned without their quotes.
 fn token_to_string(token: &Token, input: &str) -> String {
-    input[token.start..token.end].to_string()
+    match token.token_type {
+        TokenType::String => input[token.start + 1..token.end - 1].to_string(),
+        _ => input[token.start..token.end].to_string(),
+    }
+}
+
+// Replaces the escape sequences in the body of a string literal
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
 }
 
 pub fn read(input: &str) -> Result<Value, Error> {
@@ -410,16 +1058,77 @@ pub fn read(input: &str) -> Result<Value, Error> {
     parser.parse()
 }
 
+// Reads the first form of the input for the evaluator, or None when there
+// is only whitespace and comments. Operators such as + are left as
+// Value::Keyword by the parser, the evaluator wants them as symbols.
+pub fn read_str(input: &str) -> Result<Option<Value>, Error> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser::new(tokens, input);
+    if parser.at_end() {
+        return Ok(None);
+    }
+    parser.parse().map(|form| Some(operator_symbols(form)))
+}
+
+fn operator_symbols(form: Value) -> Value {
+    match form {
+        Value::Keyword(token_type) => Value::Symbol(token_type.to_string()),
+        Value::List(l) => Value::List(l.into_iter().map(operator_symbols).collect()),
+        Value::Vec(v) => Value::Vec(v.into_iter().map(operator_symbols).collect()),
+        Value::Map(m) => Value::Map(
+            m.into_iter()
+                .map(|(k, v)| (k, operator_symbols(v)))
+                .collect(),
+        ),
+        _ => form,
+    }
+}
+
+// Reads every form in the input in recovery mode. Instead of stopping at the
+// first syntax error, all of them are returned along with the forms that
+// could still be read. The forms with an error in them are left out.
+pub fn read_all_recovering(input: &str) -> (Vec<Value>, Vec<Error>) {
+    let mut errors = Vec::new();
+    let tokens = scan(input, Some(&mut errors)).unwrap_or_default();
+    let mut parser = Parser::recovering(tokens, input, errors);
+    let forms = parser.parse_all().unwrap_or_default();
+    // Lexical errors were collected before the parse ones, put them back in source order
+    let mut errors = parser.errors.unwrap_or_default();
+    errors.sort_by_key(|e| (e.location().line, e.location().column));
+    (forms, errors)
+}
+
+// Whether the form holds the placeholder of something that did not read
+fn has_placeholder(form: &Value) -> bool {
+    match form {
+        Value::Error(_) => true,
+        Value::List(l) => l.iter().any(has_placeholder),
+        Value::Vec(v) => v.iter().any(has_placeholder),
+        Value::Map(m) => m.values().any(has_placeholder),
+        _ => false,
+    }
+}
+
+// Reads every form of the input for the evaluator like read_str, or
+// returns every syntax error in it. Used to load files.
+#[allow(dead_code)]
+pub fn read_all_str(input: &str) -> Result<Vec<Value>, Vec<Error>> {
+    match read_all_recovering(input) {
+        (forms, errors) if errors.is_empty() => {
+            Ok(forms.into_iter().map(operator_symbols).collect())
+        }
+        (_, errors) => Err(errors),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use rustyline::InputMode;
-
     use super::*;
     use crate::printer::print_value;
     #[test]
     fn test_number() {
         let input = "123";
-        let mut reader = Lexer::new(&input);
+        let mut reader = Lexer::new(input);
         let token = reader.next().unwrap();
         assert_eq!(token.token_type, TokenType::Number);
         assert_eq!(
@@ -432,7 +1141,7 @@ mod tests {
     fn test_string() {
         // TODO quote escaping in strings or at least figure how to properly test it here
         let input = "\"hello\"";
-        let mut reader = Lexer::new(&input);
+        let mut reader = Lexer::new(input);
         let token = reader.next().unwrap();
         assert_eq!(token.token_type, TokenType::String);
         assert_eq!(token_to_string(&token, input).as_str(), "hello");
@@ -480,7 +1189,7 @@ mod tests {
     #[test]
     fn test_comments() {
         let input = "; hello \t\n\r   sym";
-        let mut reader = Lexer::new(&input);
+        let mut reader = Lexer::new(input);
         let token = reader.next().unwrap();
         assert_eq!(token.token_type, TokenType::Identifier);
         assert_eq!(token_to_string(&token, input), "sym");
@@ -490,22 +1199,23 @@ mod tests {
     fn test_tokenize() {
         let input = "(+ 1 2)";
         let tokens = tokenize(input).unwrap();
-        assert_eq!(tokens.len(), 5);
+        assert_eq!(tokens.len(), 6);
         assert_eq!(tokens[0].token_type, TokenType::LeftParen);
         assert_eq!(tokens[1].token_type, TokenType::Plus);
         assert_eq!(tokens[2].token_type, TokenType::Number);
         assert_eq!(tokens[3].token_type, TokenType::Number);
         assert_eq!(tokens[4].token_type, TokenType::RightParen);
+        assert_eq!(tokens[5].token_type, TokenType::EOF);
 
         let input = "\"hello\"";
         let tokens = tokenize(input).unwrap();
-        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens.len(), 2);
         assert_eq!(tokens[0].token_type, TokenType::String);
         assert_eq!(tokens[1].token_type, TokenType::EOF);
 
         let input = "(- 1 2) (* 1 2) (/ 1 2) (= 1 2) (> 1 2) (< 1 2) (>= 1 2) (<= 1 2)";
         let tokens = tokenize(input).unwrap();
-        assert_eq!(tokens.len(), 17);
+        assert_eq!(tokens.len(), 41);
         assert_eq!(tokens[0].token_type, TokenType::LeftParen);
         assert_eq!(tokens[1].token_type, TokenType::Minus);
         assert_eq!(tokens[2].token_type, TokenType::Number);
@@ -526,33 +1236,34 @@ mod tests {
         assert_eq!(tokens[17].token_type, TokenType::Number);
         assert_eq!(tokens[18].token_type, TokenType::Number);
         assert_eq!(tokens[19].token_type, TokenType::RightParen);
-        // assert_eq!(tokens[20].token_type, TokenType::LeftParen);
-        // assert_eq!(tokens[21].token_type, TokenType::Greater);
-        // assert_eq!(tokens[22].token_type, TokenType::Number);
-        // assert_eq!(tokens[23].token_type, TokenType::Number);
-        // assert_eq!(tokens[24].token_type, TokenType::RightParen);
-        // assert_eq!(tokens[25].token_type, TokenType::LeftParen);
-        // assert_eq!(tokens[26].token_type, TokenType::Less);
-        // assert_eq!(tokens[27].token_type, TokenType::Number);
-        // assert_eq!(tokens[28].token_type, TokenType::Number);
-        // assert_eq!(tokens[29].token_type, TokenType::RightParen);
-        // assert_eq!(tokens[30].token_type, TokenType::LeftParen);
-        // assert_eq!(tokens[31].token_type, TokenType::GreaterEqual);
-        // assert_eq!(tokens[32].token_type, TokenType::Number);
-        // assert_eq!(tokens[33].token_type, TokenType::Number);
-        // assert_eq!(tokens[34].token_type, TokenType::RightParen);
-        // assert_eq!(tokens[35].token_type, TokenType::LeftParen);
-        // assert_eq!(tokens[36].token_type, TokenType::LessEqual);
-        // assert_eq!(tokens[37].token_type, TokenType::Number);
-        // assert_eq!(tokens[38].token_type, TokenType::Number);
-        // assert_eq!(tokens[39].token_type, TokenType::RightParen);
+        assert_eq!(tokens[20].token_type, TokenType::LeftParen);
+        assert_eq!(tokens[21].token_type, TokenType::Greater);
+        assert_eq!(tokens[22].token_type, TokenType::Number);
+        assert_eq!(tokens[23].token_type, TokenType::Number);
+        assert_eq!(tokens[24].token_type, TokenType::RightParen);
+        assert_eq!(tokens[25].token_type, TokenType::LeftParen);
+        assert_eq!(tokens[26].token_type, TokenType::Less);
+        assert_eq!(tokens[27].token_type, TokenType::Number);
+        assert_eq!(tokens[28].token_type, TokenType::Number);
+        assert_eq!(tokens[29].token_type, TokenType::RightParen);
+        assert_eq!(tokens[30].token_type, TokenType::LeftParen);
+        assert_eq!(tokens[31].token_type, TokenType::GreaterEqual);
+        assert_eq!(tokens[32].token_type, TokenType::Number);
+        assert_eq!(tokens[33].token_type, TokenType::Number);
+        assert_eq!(tokens[34].token_type, TokenType::RightParen);
+        assert_eq!(tokens[35].token_type, TokenType::LeftParen);
+        assert_eq!(tokens[36].token_type, TokenType::LessEqual);
+        assert_eq!(tokens[37].token_type, TokenType::Number);
+        assert_eq!(tokens[38].token_type, TokenType::Number);
+        assert_eq!(tokens[39].token_type, TokenType::RightParen);
+        assert_eq!(tokens[40].token_type, TokenType::EOF);
     }
 
     #[test]
     fn test_parser() {
         println!("{:?}", TokenType::Quasiquote);
         let input = "(  +   1   2   ) ; should be ignored";
-        let tokens = tokenize(&input).unwrap();
+        let tokens = tokenize(input).unwrap();
         let mut parser = Parser::new(tokens, input);
         let ast = parser.parse().unwrap();
         print_value(&ast);
@@ -566,4 +1277,136 @@ mod tests {
 
         assert_eq!(ast.to_string(), "(+ 1 2)");
     }
+
+    #[test]
+    fn test_error_location() {
+        let input = "(def a 1)\n(foo ])\n";
+        let err = read_all_recovering(input).1.remove(0);
+        let location = Location {
+            line: 2,
+            column: 6,
+            snippet: "(foo ])".to_string(),
+        };
+        assert_eq!(
+            err,
+            Error::UnexpectedToken(TokenType::RightBracket, location.clone())
+        );
+        assert_eq!(
+            err.to_string(),
+            "unexpected ']' at line 2, column 6:\n(foo ])\n     ^"
+        );
+
+        let err = read("  (+ 1 \"two)").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated string starting at line 1, column 8:\n  (+ 1 \"two)\n       ^"
+        );
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        match read("[1 (2 3)") {
+            Err(Error::Unbalanced(TokenType::LeftBracket, location)) => {
+                assert_eq!((location.line, location.column), (1, 1))
+            }
+            res => panic!("expected an unbalanced error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        let input = "(a ? b)\n)\n(c \"ok\")\n{1}\n(d";
+        let (forms, errors) = read_all_recovering(input);
+        let lines: Vec<usize> = errors.iter().map(|e| e.location().line).collect();
+        assert_eq!(lines, vec![1, 2, 4, 5]);
+        let forms: Vec<String> = forms.iter().map(|f| f.to_string()).collect();
+        assert_eq!(forms, vec!["(c \"ok\")"]);
+    }
+
+    #[test]
+    fn test_read_all_str() {
+        let forms = read_all_str("(+ 1 2) [3]").unwrap();
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[0].to_string(), "(+ 1 2)");
+        assert!(matches!(forms[0], Value::List(ref l) if l[0] == Value::Symbol("+".to_string())));
+
+        let lines: Vec<usize> = read_all_str("(a ])\n(b)\n(c")
+            .unwrap_err()
+            .iter()
+            .map(|e| e.location().line)
+            .collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let read_str = |input: &str| read(input).unwrap().to_string();
+        assert_eq!(read_str("(a #_ b c)"), "(a c)");
+        assert_eq!(read_str("#_ #_ a b c"), "c");
+        assert_eq!(read_str("#\"\\d+\""), "(re-pattern \"\\\\d+\")");
+        assert_eq!(read_str("#(f % %3)"), "(fn* [%1 %2 %3] (f %1 %3))");
+        assert_eq!(read_str("#(apply f %&)"), "(fn* [& %&] (apply f %&))");
+        assert_eq!(
+            read_str("#my/tag [1 2]"),
+            "(tagged-literal \"my/tag\" (quote [1 2]))"
+        );
+        assert_eq!(read_str("#inst \"1970-01-02\""), "86400000");
+        assert_eq!(
+            read_str("#inst \"2023-11-17T08:30:00.25+01:00\""),
+            "1700206200250"
+        );
+
+        assert!(matches!(
+            read("#inst \"2023-13-01\""),
+            Err(Error::InvalidInstant(..))
+        ));
+        assert!(matches!(read("#(a #(b))"), Err(Error::NestedAnonFn(_))));
+        assert!(matches!(
+            read("#!"),
+            Err(Error::UnexpectedCharacter('!', _))
+        ));
+    }
+
+    #[test]
+    fn test_qualified_symbol() {
+        let tokens = tokenize("(mal.core/str foo.bar/baz / 2)").unwrap();
+        let texts: Vec<&str> = tokens[1..5]
+            .iter()
+            .map(|t| t.text("(mal.core/str foo.bar/baz / 2)"))
+            .collect();
+        assert_eq!(texts, vec!["mal.core/str", "foo.bar/baz", "/", "2"]);
+        assert_eq!(tokens[3].token_type, TokenType::Slash);
+    }
+
+    #[test]
+    fn test_reader_macros() {
+        let read_str = |input: &str| read(input).unwrap().to_string();
+        assert_eq!(read_str("'a"), "(quote a)");
+        assert_eq!(
+            read_str("`(a ~b ~@c)"),
+            "(quasiquote (a (unquote b) (splice-unquote c)))"
+        );
+        assert_eq!(read_str("@x"), "(deref x)");
+        assert_eq!(read_str("^:dynamic *x*"), "(with-meta *x* :dynamic)");
+        assert_eq!(read_str("^{:a 1} [1, 2]"), "(with-meta [1 2] {:a 1})");
+        assert!(read(":kw").unwrap().keyword_q());
+    }
+
+    #[test]
+    fn test_char() {
+        let input = r"[\a \newline \space \é \( \u0007]";
+        let expected = vec!['a', '\n', ' ', 'é', '(', '\u{7}'];
+        assert_eq!(
+            read(input).unwrap(),
+            Value::Vec(expected.into_iter().map(Value::Char).collect())
+        );
+        assert_eq!(
+            read(input).unwrap().to_string(),
+            r"[\a \newline \space \é \( \u0007]"
+        );
+        assert!(matches!(
+            read(r"\newlin"),
+            Err(Error::InvalidCharacter(ref text, _)) if text == r"\newlin"
+        ));
+    }
 }
@@ -1,15 +1,33 @@
+extern crate fnv;
 extern crate rustyline;
 
+#[macro_use]
+#[allow(dead_code)]
+mod sync;
+
+// Lossless syntax trees for tooling, the REPL itself only needs the reader
+#[allow(dead_code)]
+mod cst;
+// Shared with the evaluator of the later steps, values can hold closures,
+// continuations and their envs, which this step never makes
+#[allow(dead_code)]
+mod cont;
+#[allow(dead_code)]
+mod env;
 mod error;
+#[allow(dead_code)]
+mod gc;
+#[allow(dead_code)]
 mod printer;
 mod reader;
+#[allow(dead_code)]
 mod types;
 
 use error::Error;
 use printer::print_value;
-use reader::read;
+use reader::{read, read_all_recovering};
 use rustyline::error::ReadlineError;
-use rustyline::{DefaultEditor, Result as ReadlineResult};
+use rustyline::DefaultEditor;
 use std::result::Result;
 use types::Value;
 
@@ -23,12 +41,37 @@ fn print(ast: &Value) -> Result<(), Error> {
 }
 
 fn read_eval_print(input: &str) -> Result<(), Error> {
-    let mut ast = read(input).unwrap();
+    let mut ast = read(input)?;
     eval(&mut ast)?;
     print(&ast)?;
     Ok(())
 }
+
+// Reads a whole file in recovery mode so every syntax error in it is reported at once
+fn read_print_file(path: &str) -> bool {
+    let input = match std::fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("Error: {}: {}", path, err);
+            return false;
+        }
+    };
+    let (forms, errors) = read_all_recovering(&input);
+    for form in forms.iter() {
+        print_value(form);
+    }
+    for err in errors.iter() {
+        eprintln!("Error: {}: {}", path, err);
+    }
+    errors.is_empty()
+}
+
 fn main() -> Result<(), Error> {
+    // Invoked with arguments
+    if let Some(path) = std::env::args().nth(1) {
+        std::process::exit(if read_print_file(&path) { 0 } else { 1 });
+    }
+
     let mut rl = DefaultEditor::new().unwrap();
     #[cfg(feature = "with-file-history")]
     if rl.load_history("history.txt").is_err() {
@@ -39,7 +82,9 @@ fn main() -> Result<(), Error> {
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str()).unwrap();
-                read_eval_print(&line)?;
+                if let Err(err) = read_eval_print(&line) {
+                    println!("Error: {}", err);
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
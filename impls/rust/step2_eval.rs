@@ -12,6 +12,8 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+#[macro_use]
+mod sync;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
@@ -23,6 +25,7 @@ mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
 mod env;
+mod gc;
 
 pub type Env = FnvHashMap<String, MalVal>;
 
@@ -13,12 +13,15 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+#[macro_use]
+mod sync;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
 use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
 mod env;
+mod gc;
 mod printer;
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
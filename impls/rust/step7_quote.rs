@@ -13,11 +13,15 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+#[macro_use]
+mod sync;
 #[macro_use]
 mod types;
 use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
 use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
 mod env;
+mod gc;
+mod limits;
 mod printer;
 mod reader;
 use crate::env::{env_bind, env_get, env_new, env_set, env_sets, Env};
@@ -13,12 +13,16 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+#[macro_use]
+mod sync;
 #[macro_use]
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalErr::{ErrLimit, ErrMalVal, ErrString};
 use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
 use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
 mod env;
+mod gc;
+mod limits;
 mod printer;
 mod reader;
 use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
@@ -220,7 +224,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         Err(ref e) if l.len() >= 3 => {
                             let exc = match e {
                                 ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
+                                ErrString(s) | ErrLimit(s) => Str(s.to_string()),
                             };
                             match l[2].clone() {
                                 List(c, _) => {
@@ -1,6 +1,6 @@
-#![allow(non_snake_case)]
-
-use std::rc::Rc;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::time::Duration;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 use itertools::Itertools;
@@ -13,71 +13,151 @@ extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::DefaultEditor;
 
 #[macro_use]
+mod sync;
 mod types;
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::core::{
+    defmethod, defmulti, defprotocol, error_value, extend_protocol, gensym, int_value,
+    native_error, pop_handlers, pop_restarts, protocol_impl, protocol_method, protocol_methods,
+    push_handlers, push_restarts, realize_printed, restart_ids, restart_transfer, BUILTIN_TYPES,
+};
+use crate::types::MalErr::{ErrLimit, ErrMalVal, ErrString};
+use crate::types::Value::{Boolean, List, Map, Null, Symbol};
+use crate::types::{
+    error, format_error, func, hash_map, keyword, list, Closure, Continuation, MalArgs, MalErr,
+    MalRet, Value,
+};
+mod cont;
+use crate::cont::{Frame, Loop, SeqKind, Tail};
+// Lossless syntax trees for tooling, the evaluator only needs the reader,
+// and not its recovery mode, which reports every error of a file at once
+#[allow(dead_code)]
+mod cst;
 mod env;
+#[allow(dead_code)]
+mod error;
+mod gc;
+mod limits;
+mod loader;
+mod memory;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
-#[macro_use]
+use crate::env::{
+    current_ns, dynamic_var, env_bind, env_get, env_new, env_set, env_sets, in_ns, ns_env,
+    pop_bindings, push_bindings, resolve_var, set_var_meta, Env, CORE_NS,
+};
+use crate::limits::{limit_exceeded, set_limits, Limits};
+use crate::printer::{pr_str_pretty, set_print_limits, PrettyConfig, PrintLimits};
+use crate::sync::Shared;
 mod core;
 
 // read
-fn read(str: &str) -> MalRet {
-    reader::read_str(str.to_string())
+fn read(str: &str) -> Result<Option<Value>, MalErr> {
+    reader::read_str(str).map_err(|e| ErrString(e.to_string()))
 }
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
-    let mut acc = list![];
+thread_local! {
+    // How many macros are being expanded, quasiquote only qualifies symbols
+    // in their bodies so that plain quasiquoted data keeps its symbols as is
+    static EXPANDING: Cell<usize> = const { Cell::new(0) };
+}
+
+fn sym(s: &str) -> Value {
+    Symbol(s.to_string())
+}
+
+// Returns a closure made by this step's evaluator
+fn closure(params: Value, body: Value, env: Env) -> Value {
+    Value::Closure(Shared::new(Closure {
+        eval,
+        params,
+        body,
+        env,
+        is_macro: false,
+        meta: Null,
+    }))
+}
+
+// The state of one quasiquote expansion
+struct SyntaxQuote<'a> {
+    env: &'a Env,
+    // The generated symbol for each x# seen so far
+    gensyms: FnvHashMap<String, String>,
+}
+
+impl SyntaxQuote<'_> {
+    fn new(env: &Env) -> SyntaxQuote<'_> {
+        SyntaxQuote {
+            env,
+            gensyms: FnvHashMap::default(),
+        }
+    }
+
+    // Turns x# into a fresh symbol, the same one each time in this quasiquote,
+    // and in macro bodies qualifies symbols naming vars with their namespace
+    fn symbol(&mut self, s: &str) -> Value {
+        if s.len() > 1 && s.ends_with('#') {
+            let name = &s[..s.len() - 1];
+            return Symbol(
+                self.gensyms
+                    .entry(s.to_string())
+                    .or_insert_with(|| format!("{}__auto__", gensym(&format!("{}__", name))))
+                    .clone(),
+            );
+        }
+        let sym = Symbol(s.to_string());
+        if EXPANDING.with(|e| e.get()) > 0 {
+            if let Some(qualified) = resolve_var(self.env, &sym) {
+                return Symbol(qualified);
+            }
+        }
+        sym
+    }
+}
+
+fn qq_iter(elts: &[Value], sq: &mut SyntaxQuote) -> Value {
+    let mut acc = list(vec![]);
     for elt in elts.iter().rev() {
-        if let List(v, _) = elt {
-            if v.len() == 2 {
-                if let Sym(ref s) = v[0] {
-                    if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
-                        continue;
-                    }
-                }
+        if let List(v) = elt.strip() {
+            if v.len() == 2 && v[0] == sym("splice-unquote") {
+                acc = list(vec![sym("concat"), v[1].clone(), acc]);
+                continue;
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list(vec![sym("cons"), quasiquote(elt, sq), acc]);
     }
-    return acc;
+    acc
 }
 
-fn quasiquote(ast: &MalVal) -> MalVal {
-    match ast {
-        List(v, _) => {
-            if v.len() == 2 {
-                if let Sym(ref s) = v[0] {
-                    if s == "unquote" {
-                        return v[1].clone();
-                    }
-                }
+fn quasiquote(ast: &Value, sq: &mut SyntaxQuote) -> Value {
+    match ast.strip() {
+        List(v) => {
+            if v.len() == 2 && v[0] == sym("unquote") {
+                return v[1].clone();
             }
-            return qq_iter(&v);
-        },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+            qq_iter(&ast.seq().unwrap(), sq)
+        }
+        Value::Vec(v) => list(vec![sym("vec"), qq_iter(v, sq)]),
+        Symbol(s) => list(vec![sym("quote"), sq.symbol(s)]),
+        Map(_) => list(vec![sym("quote"), ast.clone()]),
         _ => ast.clone(),
     }
 }
 
-fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
-    match ast {
-        List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
-                    _ => None,
-                },
+fn is_macro_call(ast: &Value, env: &Env) -> Option<(Value, MalArgs)> {
+    match ast.strip() {
+        List(v) => match v.front() {
+            // env_get also finds macros through qualified symbols like foo/when
+            Some(a0 @ Symbol(_)) => match env_get(env, a0) {
+                Ok(f @ Value::Closure(_)) if is_macro(&f) => {
+                    Some((f, v.iter().skip(1).cloned().collect()))
+                }
                 _ => None,
             },
             _ => None,
@@ -86,278 +166,1404 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     }
 }
 
-fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
+fn is_macro(f: &Value) -> bool {
+    matches!(f, Value::Closure(c) if c.is_macro)
+}
+
+fn expand_macro(mf: &Value, args: MalArgs) -> MalRet {
+    EXPANDING.with(|e| e.set(e.get() + 1));
+    let res = mf.apply(args);
+    EXPANDING.with(|e| e.set(e.get() - 1));
+    res
+}
+
+fn macroexpand(mut ast: Value, env: &Env) -> (bool, MalRet) {
     let mut was_expanded = false;
     while let Some((mf, args)) = is_macro_call(&ast, env) {
         //println!("macroexpand 1: {:?}", ast);
-        ast = match mf.apply(args) {
+        ast = match expand_macro(&mf, args) {
             Err(e) => return (false, Err(e)),
             Ok(a) => a,
         };
         //println!("macroexpand 2: {:?}", ast);
         was_expanded = true;
     }
-    ((was_expanded, Ok(ast)))
+    (was_expanded, Ok(ast))
+}
+
+// Expands the macros in the form and in all its subforms, except quoted ones
+fn macroexpand_all(ast: Value, env: &Env) -> MalRet {
+    let ast = macroexpand(ast, env).1?;
+    let expand = |forms: Vec<Value>| -> Result<MalArgs, MalErr> {
+        forms.into_iter().map(|f| macroexpand_all(f, env)).collect()
+    };
+    match ast.strip() {
+        List(ref l) => match l.front() {
+            Some(Symbol(ref s)) if s == "quote" || s == "quasiquote" => Ok(ast.clone()),
+            _ => Ok(list(expand(ast.seq().unwrap())?)),
+        },
+        Value::Vec(ref v) => Ok(Value::Vec(expand(v.clone())?)),
+        Map(ref hm) => {
+            let mut new_hm: HashMap<String, Value> = HashMap::new();
+            for (k, v) in hm.iter() {
+                new_hm.insert(k.to_string(), macroexpand_all(v.clone(), env)?);
+            }
+            Ok(Map(new_hm))
+        }
+        _ => Ok(ast),
+    }
+}
+
+// Returns a function calling (dispatcher (quote name) args) with its
+// arguments, with the name in its metadata under the key
+fn dispatch_fn(dispatcher: &str, key: &str, name: &str) -> MalRet {
+    let f = eval(
+        list(vec![
+            sym("fn*"),
+            list(vec![sym("&"), sym("args")]),
+            list(vec![
+                sym(dispatcher),
+                list(vec![sym("quote"), sym(name)]),
+                sym("args"),
+            ]),
+        ]),
+        ns_env(CORE_NS),
+    )?;
+    let meta = hash_map(vec![keyword(key), sym(name)])?;
+    f.with_meta(&meta)
+}
+
+// Returns the qualified name of the protocol the symbol refers to
+fn protocol_name(env: &Env, sym: &Value) -> Result<String, MalErr> {
+    match eval(sym.clone(), env.clone())? {
+        Symbol(ref p) if protocol_methods(p).is_some() => Ok(p.to_string()),
+        _ => Err(ErrString(format!("{} is not a protocol", sym.pr_str(true)))),
+    }
+}
+
+// Returns the type name a symbol given to extend-type stands for, either
+// one of the builtin types or a record type defined by defrecord
+fn type_key(env: &Env, sym: &Value) -> Result<String, MalErr> {
+    match sym {
+        Symbol(ref s) if BUILTIN_TYPES.contains(&s.as_str()) => Ok(s.to_string()),
+        Symbol(_) => match eval(sym.clone(), env.clone())? {
+            Symbol(ref t) => Ok(t.to_string()),
+            _ => Err(ErrString(format!("{} is not a type", sym.pr_str(true)))),
+        },
+        _ => Err(ErrString("expected a type name".to_string())),
+    }
+}
+
+// Adds the implementation (method [this ...] body...) of a method of the
+// protocol for the type
+fn add_impl(env: &Env, protocol: &str, t: &str, form: &[Value]) -> MalRet {
+    let method = match (form.first(), protocol.rfind('/')) {
+        (Some(Symbol(ref m)), Some(i)) if form.len() >= 2 => format!("{}/{}", &protocol[..i], m),
+        _ => return error("expected (method [this ...] body...)"),
+    };
+    if !protocol_methods(protocol).is_some_and(|ms| ms.contains(&method)) {
+        return error(&format!("{} is not a method of {}", method, protocol));
+    }
+    let f = eval(
+        list(vec![sym("fn*"), form[1].clone(), do_form(&form[2..])]),
+        env.clone(),
+    )?;
+    extend_protocol(&method, t, f);
+    Ok(Null)
+}
+
+// Returns a single form evaluating the forms of a body in order
+fn do_form(body: &[Value]) -> Value {
+    match body.len() {
+        0 => Null,
+        1 => body[0].clone(),
+        _ => {
+            let mut forms = vec![sym("do")];
+            forms.extend_from_slice(body);
+            list(forms)
+        }
+    }
+}
+
+// The special forms making functions over the env they are evaluated in
+const CAPTURING_FORMS: &[&str] = &[
+    "fn*",
+    "reset",
+    "shift",
+    "restart-case",
+    "defmethod",
+    "extend-type",
+    "extend-protocol",
+    "defrecord",
+];
+
+// Returns whether evaluating the form may make a function over the env it
+// is evaluated in. Macro calls are assumed to, whatever they expand to.
+fn may_capture(ast: &Value, env: &Env) -> bool {
+    match ast.strip() {
+        List(l) => match l.front() {
+            Some(Symbol(s)) if s == "quote" => false,
+            Some(Symbol(s)) if CAPTURING_FORMS.contains(&s.as_str()) => true,
+            _ if is_macro_call(ast, env).is_some() => true,
+            _ => l.iter().any(|f| may_capture(f, env)),
+        },
+        Value::Vec(v) => v.iter().any(|f| may_capture(f, env)),
+        Map(hm) => hm.values().any(|f| may_capture(f, env)),
+        _ => false,
+    }
+}
+
+// Checks, before a form runs, that every recur in it is inside a loop* and
+// in tail position. Macros are expanded as far as they are defined already.
+fn check_recur(ast: &Value, env: &Env, tail: bool, in_loop: bool) -> Result<(), MalErr> {
+    let ast = match macroexpand(ast.clone(), env) {
+        (true, Ok(expanded)) => expanded,
+        _ => ast.clone(),
+    };
+    let l = match ast.strip() {
+        List(ref l) if !l.is_empty() => ast.seq().unwrap(),
+        Value::Vec(ref v) => return check_body(v, env, false, in_loop),
+        Map(ref hm) => {
+            for v in hm.values() {
+                check_recur(v, env, false, in_loop)?;
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+    match l[0] {
+        Symbol(ref s) if s == "recur" => {
+            if !in_loop {
+                return error(&format!("recur outside of loop*: {}", ast.pr_str(true)));
+            }
+            if !tail {
+                return error(&format!(
+                    "recur is not in tail position, it would not end the iteration: {}",
+                    ast.pr_str(true)
+                ));
+            }
+            check_body(&l[1..], env, false, in_loop)
+        }
+        Symbol(ref s) if s == "quote" || s == "quasiquote" => Ok(()),
+        // The target is not evaluated, and the metadata defn gives it holds
+        // the defn form itself
+        Symbol(ref s) if s == "def!" => check_body(l.get(2..).unwrap_or(&[]), env, false, in_loop),
+        // A function body is not part of the loop around it
+        Symbol(ref s) if s == "fn*" => check_body(l.get(2..).unwrap_or(&[]), env, true, false),
+        Symbol(ref s) if s == "loop*" && l.len() >= 2 => {
+            check_recur(&l[1], env, false, in_loop)?;
+            // The body ends each iteration, whether or not the loop* is in tail position
+            check_body(&l[2..], env, true, true)
+        }
+        Symbol(ref s) if s == "let*" && l.len() >= 2 => {
+            check_recur(&l[1], env, false, in_loop)?;
+            check_body(&l[2..], env, tail, in_loop)
+        }
+        Symbol(ref s) if s == "if" && l.len() >= 2 => {
+            check_recur(&l[1], env, false, in_loop)?;
+            for branch in l[2..].iter() {
+                check_recur(branch, env, tail, in_loop)?;
+            }
+            Ok(())
+        }
+        Symbol(ref s) if s == "do" => check_body(&l[1..], env, tail, in_loop),
+        // Everything else evaluates its forms before it is done
+        _ => check_body(&l, env, false, in_loop),
+    }
+}
+
+// Checks the forms of a body, of which only the last one can be in tail position
+fn check_body(forms: &[Value], env: &Env, tail: bool, in_loop: bool) -> Result<(), MalErr> {
+    for (i, form) in forms.iter().enumerate() {
+        check_recur(form, env, tail && i == forms.len() - 1, in_loop)?;
+    }
+    Ok(())
+}
+
+// Returns the body of the first catch* clause matching the error, and the
+// env to run it in, or None when no clause does. The clauses are (catch* e
+// body), which catches everything, (catch* :kind e body...), which catches
+// errors whose :type is :kind, and (catch* pred e body...), which catches
+// errors pred returns true for.
+fn find_catch(
+    err: &MalErr,
+    catches: &[Vec<Value>],
+    env: &Env,
+) -> Result<Option<(Value, Env)>, MalErr> {
+    // Invoking a restart unwinds through try* to its restart-case, and going
+    // over a limit ends the evaluation
+    if restart_transfer(err).is_some() || limit_exceeded(err).is_some() {
+        return Ok(None);
+    }
+    let exc = error_value(err);
+    for c in catches.iter() {
+        let (matched, exc, body) = match c.len() {
+            // mal's catch* binds the message of errors from native functions,
+            // as its tests expect. The clauses below match on the :type of
+            // the condition, so they bind the condition, whose :message is
+            // that same string.
+            3 => match err {
+                ErrString(ref s) | ErrLimit(ref s) => {
+                    (true, Value::String(s.to_string()), c[2].clone())
+                }
+                ErrMalVal(ref mv) => (true, mv.clone(), c[2].clone()),
+            },
+            _ => {
+                let matched = match c[1] {
+                    Value::String(_) if c[1].keyword_q() => exc.field("type") == Some(&c[1]),
+                    _ => eval(c[1].clone(), env.clone())?
+                        .apply(vec![exc.clone()])?
+                        .truthy(),
+                };
+                (matched, exc.clone(), do_form(&c[3..]))
+            }
+        };
+        if matched {
+            let bind = if c.len() == 3 { &c[1] } else { &c[2] };
+            let catch_env = env_bind(Some(env.clone()), &list(vec![bind.clone()]), vec![exc])?;
+            return Ok(Some((body, catch_env)));
+        }
+    }
+    Ok(None)
+}
+
+// Splits the target of def!, which may carry reader metadata as in
+// (def! ^:dynamic *x* 1), into the symbol and the metadata map
+fn def_target(target: &Value) -> Result<(Value, Value), MalErr> {
+    let l = match target {
+        Symbol(_) => return Ok((target.clone(), Null)),
+        List(l) => l,
+        _ => return error("def!: expected a symbol"),
+    };
+    // Macros like defn produce mal.core/with-meta, see SyntaxQuote
+    if l.len() != 3 || (l[0] != sym("with-meta") && l[0] != sym("mal.core/with-meta")) {
+        return error("def!: expected a symbol");
+    }
+    let meta = match l[2] {
+        Value::String(_) if l[2].keyword_q() => hash_map(vec![l[2].clone(), Boolean(true)])?,
+        Map(_) => l[2].clone(),
+        _ => return error("def!: metadata must be a keyword or a map"),
+    };
+    match l[1] {
+        Symbol(_) => Ok((l[1].clone(), meta)),
+        _ => error("def!: expected a symbol"),
+    }
+}
+
+// Hands *print-length* and *print-level* to the printer when one of the
+// vars, given by qualified name, is one of them. Only the vars of mal.core
+// count, a namespace defining its own *print-length* doesn't change printing.
+fn sync_print_limits<'a>(mut vars: impl Iterator<Item = &'a str>) {
+    let core_var = |name: &str| format!("{}/{}", CORE_NS, name);
+    if !vars.any(|var| var == core_var("*print-length*") || var == core_var("*print-level*")) {
+        return;
+    }
+    let limit = |name: &str| match env_get(&ns_env(CORE_NS), &Symbol(name.to_string())) {
+        Ok(ref n) => int_value(n).filter(|n| *n >= 0).map(|n| n as usize),
+        _ => None,
+    };
+    set_print_limits(PrintLimits {
+        length: limit("*print-length*"),
+        level: limit("*print-level*"),
+    });
+}
+
+// The evaluator
+//
+// eval runs on an explicit stack of frames, see cont.rs, rather than on the
+// Rust stack, so that shift can take the rest of its reset's body off the
+// stack as a continuation and calling the continuation can push it back.
+// The rest of the body then runs once per call, from where the shift was.
+// Native functions calling back into mal, like map or swap!, start an eval
+// of their own, so a shift only reaches the resets of the eval it runs in.
+
+// What the evaluator does next
+enum Control {
+    // Evaluates the form, the tail of the loop* if any
+    Eval(Value, Env, Tail),
+    // Hands the value to the frame on top of the stack
+    Return(Value),
+    // Unwinds the stack with the error
+    Throw(MalErr),
+}
+
+use Control::{Eval, Return, Throw};
+
+// The stack of one eval, each frame counts as a level of recursion for the
+// depth limit
+struct Stack(Vec<Frame>);
+
+impl Stack {
+    fn push(&mut self, frame: Frame) -> Result<(), MalErr> {
+        limits::deeper()?;
+        self.0.push(frame);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        let frame = self.0.pop()?;
+        limits::shallower(1);
+        Some(frame)
+    }
+
+    // Pushes a frame and establishes its dynamic state
+    fn enter(&mut self, frame: Frame) -> Result<(), MalErr> {
+        self.push(frame)?;
+        restore(&self.0[self.0.len() - 1]);
+        Ok(())
+    }
+
+    // Takes the frames above the innermost reset off the stack, ending the
+    // dynamic state they established
+    fn capture(&mut self) -> Result<Vec<Frame>, MalErr> {
+        let start = match self.0.iter().rposition(|f| matches!(f, Frame::Reset)) {
+            Some(i) => i + 1,
+            None => {
+                return error(
+                    "shift outside of reset, or in a function called by a native function",
+                )
+            }
+        };
+        let frames = self.0.split_off(start);
+        limits::shallower(frames.len());
+        frames.iter().rev().for_each(suspend);
+        Ok(frames)
+    }
+
+    // Pushes copies of the frames of the continuation back, above a reset
+    fn reinstate(&mut self, k: &Continuation) -> Result<(), MalErr> {
+        // A continuation called in tail position of its own reset needs no
+        // other, so loops calling continuations run in constant space
+        if !matches!(self.0.last(), Some(Frame::Reset)) {
+            self.push(Frame::Reset)?;
+        }
+        for frame in k.frames.iter() {
+            self.enter(frame.clone())?;
+        }
+        Ok(())
+    }
 }
 
-fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
+// Ends the dynamic state of a frame leaving the stack
+fn suspend(frame: &Frame) {
+    match frame {
+        Frame::Binding { vars } => {
+            pop_bindings(vars.len());
+            sync_print_limits(vars.iter().map(|(var, _)| var.as_str()));
+        }
+        Frame::Handlers(handlers) => pop_handlers(handlers.len()),
+        Frame::Restarts { names, .. } => pop_restarts(names.len()),
+        _ => (),
+    }
+}
+
+// Establishes the dynamic state of a frame entering the stack
+fn restore(frame: &Frame) {
+    match frame {
+        Frame::Binding { vars } => {
+            push_bindings(vars.clone());
+            sync_print_limits(vars.iter().map(|(var, _)| var.as_str()));
+        }
+        Frame::Handlers(handlers) => push_handlers(handlers.clone()),
+        Frame::Restarts { names, first, .. } => push_restarts(names, *first),
+        _ => (),
+    }
+}
+
+// Returns the value of a symbol or of a form evaluating to itself, which
+// needs no frame
+fn immediate(ast: &Value, env: &Env) -> Option<MalRet> {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
-        List(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+        Symbol(_) => Some(env_get(env, ast)),
+        List(_) | Value::Vec(_) | Map(_) | Value::Meta(..) => None,
+        _ => Some(Ok(ast.clone())),
+    }
+}
+
+// Evaluates the forms in order for a Seq frame
+fn eval_seq(
+    kind: SeqKind,
+    forms: Vec<Value>,
+    env: Env,
+    stack: &mut Stack,
+) -> Result<Control, MalErr> {
+    let done = Vec::with_capacity(forms.len());
+    let mut todo = forms;
+    todo.reverse();
+    seq_next(kind, done, todo, env, stack)
+}
+
+fn seq_next(
+    kind: SeqKind,
+    mut done: Vec<Value>,
+    mut todo: Vec<Value>,
+    env: Env,
+    stack: &mut Stack,
+) -> Result<Control, MalErr> {
+    while let Some(form) = todo.pop() {
+        match immediate(&form, &env) {
+            Some(v) => done.push(v?),
+            None => {
+                stack.push(Frame::Seq {
+                    kind,
+                    done,
+                    todo,
+                    env: env.clone(),
+                })?;
+                return Ok(Eval(form, env, None));
             }
-            Ok(list!(lst))
         }
-        Vector(v, _) => {
-            let mut lst: MalArgs = vec![];
-            for a in v.iter() {
-                lst.push(eval(a.clone(), env.clone())?)
+    }
+    match kind {
+        SeqKind::Call => {
+            let args = done.split_off(1);
+            apply_fn(done.pop().unwrap(), args, stack)
+        }
+        SeqKind::List(meta) => with_meta(list(done), meta),
+        SeqKind::Vec(meta) => with_meta(Value::Vec(done), meta),
+        SeqKind::Map(keys, meta) => with_meta(Map(keys.into_iter().zip(done).collect()), meta),
+        SeqKind::Recur(target) => {
+            // Unless the body may make closures keeping the values of the
+            // iteration, the loop variables are rebound in place
+            let env = match target.fresh {
+                true => env_new(target.env.outer.clone()),
+                false => target.env.clone(),
+            };
+            for (sym, val) in target.syms.iter().zip(done) {
+                env_set(&env, sym.clone(), val)?;
             }
-            Ok(vector!(lst))
+            Ok(Eval(target.body.clone(), env, Some(target)))
         }
-        Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-            for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        SeqKind::Binding(names, body) => {
+            // The bindings end once the body is done, also when it threw
+            let vars = names.into_iter().zip(done).collect();
+            stack.enter(Frame::Binding { vars })?;
+            Ok(Eval(body, env, None))
+        }
+    }
+}
+
+fn with_meta(v: Value, meta: Option<Value>) -> Result<Control, MalErr> {
+    match meta {
+        Some(meta) => Ok(Return(v.with_meta(&meta)?)),
+        None => Ok(Return(v)),
+    }
+}
+
+// Calls the function, a closure or continuation running on this stack
+fn apply_fn(f: Value, args: MalArgs, stack: &mut Stack) -> Result<Control, MalErr> {
+    // Protocol methods go straight to the implementation for the type,
+    // without the function dispatching to it
+    let f = match f {
+        Value::Closure(ref c) => match protocol_method(&c.meta) {
+            Some(method) => protocol_impl(&method, &args)?,
+            None => f.clone(),
+        },
+        _ => f,
+    };
+    match f {
+        Value::Func(_) => match f.apply(args) {
+            Err(e @ ErrString(_)) => native_error(e).map(Return),
+            res => res.map(Return),
+        },
+        Value::Closure(c) => {
+            let env = env_bind(Some(c.env.clone()), &c.params, args)?;
+            Ok(Eval(c.body.clone(), env, None))
+        }
+        Value::Cont(ref k) if args.len() <= 1 => {
+            stack.reinstate(k)?;
+            Ok(Return(args.into_iter().next().unwrap_or(Null)))
+        }
+        _ => f.apply(args).map(Return),
+    }
+}
+
+// Binds the let* bindings left, given in reverse, then evaluates the body
+fn let_next(
+    env: Env,
+    mut binds: Vec<(Value, Value)>,
+    body: Value,
+    tail: Tail,
+    stack: &mut Stack,
+) -> Result<Control, MalErr> {
+    while let Some((sym, form)) = binds.pop() {
+        if !matches!(sym, Symbol(_)) {
+            return error("let* with non-Sym binding");
+        }
+        match immediate(&form, &env) {
+            Some(v) => {
+                env_set(&env, sym, v?)?;
+            }
+            None => {
+                stack.push(Frame::Let {
+                    env: env.clone(),
+                    sym,
+                    binds,
+                    body,
+                    tail,
+                })?;
+                return Ok(Eval(form, env, None));
+            }
+        }
+    }
+    Ok(Eval(body, env, tail))
+}
+
+// Binds the loop* variables left, given in reverse, then starts the loop
+fn loop_next(
+    env: Env,
+    mut binds: Vec<(Value, Value)>,
+    mut syms: Vec<Value>,
+    body: Value,
+    stack: &mut Stack,
+) -> Result<Control, MalErr> {
+    while let Some((sym, form)) = binds.pop() {
+        if !matches!(sym, Symbol(_)) {
+            return error("loop* with non-Sym binding");
+        }
+        match immediate(&form, &env) {
+            Some(v) => {
+                env_set(&env, sym.clone(), v?)?;
+                syms.push(sym);
+            }
+            None => {
+                stack.push(Frame::Loop {
+                    env: env.clone(),
+                    sym,
+                    binds,
+                    syms,
+                    body,
+                })?;
+                return Ok(Eval(form, env, None));
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
         }
-        _ => Ok(ast.clone()),
     }
+    let fresh = may_capture(&body, &env);
+    let target = Shared::new(Loop {
+        env: env.clone(),
+        syms,
+        body: body.clone(),
+        fresh,
+    });
+    Ok(Eval(body, env, Some(target)))
 }
 
-fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
-    let ret: MalRet;
+// Evaluates the forms of a do left, given in reverse. At the top level of a
+// namespace each form runs in the namespace current at that point, so an
+// (ns ...) inside a loaded file applies to the forms after it.
+fn do_next(
+    mut forms: Vec<Value>,
+    env: Env,
+    tail: Tail,
+    stack: &mut Stack,
+) -> Result<Control, MalErr> {
+    let form = match forms.pop() {
+        Some(form) => form,
+        None => return Ok(Return(Null)),
+    };
+    let form_env = match env.ns {
+        Some(_) => ns_env(&current_ns()),
+        None => env.clone(),
+    };
+    if forms.is_empty() {
+        return Ok(Eval(form, form_env, tail));
+    }
+    stack.push(Frame::Do { forms, env, tail })?;
+    Ok(Eval(form, form_env, None))
+}
 
-    'tco: loop {
-        ret = match ast.clone() {
-            List(l, _) => {
-                if l.len() == 0 {
-                    return Ok(ast);
+// Gives the value of the form evaluated last to the frame waiting for it
+fn resume(frame: Frame, v: Value, stack: &mut Stack) -> Result<Control, MalErr> {
+    match frame {
+        Frame::Seq {
+            kind,
+            mut done,
+            todo,
+            env,
+        } => {
+            done.push(v);
+            seq_next(kind, done, todo, env, stack)
+        }
+        Frame::If {
+            then,
+            otherwise,
+            env,
+            tail,
+        } => Ok(Eval(if v.truthy() { then } else { otherwise }, env, tail)),
+        Frame::Do { forms, env, tail } => do_next(forms, env, tail, stack),
+        Frame::Let {
+            env,
+            sym,
+            binds,
+            body,
+            tail,
+        } => {
+            env_set(&env, sym, v)?;
+            let_next(env, binds, body, tail, stack)
+        }
+        Frame::Loop {
+            env,
+            sym,
+            binds,
+            mut syms,
+            body,
+        } => {
+            env_set(&env, sym.clone(), v)?;
+            syms.push(sym);
+            loop_next(env, binds, syms, body, stack)
+        }
+        Frame::Def {
+            sym,
+            meta,
+            form,
+            env,
+        } => {
+            env_set(&env, sym.clone(), v.clone())?;
+            set_var_meta(&env, &sym, meta, form)?;
+            sync_print_limits(resolve_var(&env, &sym).as_deref().into_iter());
+            Ok(Return(v))
+        }
+        Frame::Eval => {
+            let env = ns_env(&current_ns());
+            check_recur(&v, &env, false, false)?;
+            Ok(Eval(v, env, None))
+        }
+        Frame::Binding { .. } | Frame::Handlers(_) | Frame::Restarts { .. } => {
+            suspend(&frame);
+            Ok(Return(v))
+        }
+        // The finally* body runs after the others, and an error it throws
+        // replaces the result
+        Frame::Try {
+            finally: Some(body),
+            env,
+            ..
+        } => {
+            stack.push(Frame::Finally(Ok(v)))?;
+            Ok(Eval(body, env, None))
+        }
+        Frame::Finally(res) => res.map(Return),
+        Frame::Try { .. } | Frame::Reset => Ok(Return(v)),
+    }
+}
+
+// Gives the error to the frame on top of the stack, which catches it or
+// lets it through
+fn unwind(frame: Frame, err: MalErr, stack: &mut Stack) -> Result<Control, MalErr> {
+    match frame {
+        Frame::Binding { .. } | Frame::Handlers(_) => {
+            suspend(&frame);
+            Err(err)
+        }
+        Frame::Restarts {
+            ref names,
+            first,
+            ref clauses,
+            ref env,
+        } => {
+            suspend(&frame);
+            match restart_transfer(&err) {
+                Some((id, args)) if id >= first && id < first + names.len() => {
+                    let c = clauses[id - first].seq().unwrap();
+                    let f = closure(c[1].clone(), do_form(&c[2..]), env.clone());
+                    apply_fn(f, args, stack)
                 }
-                match macroexpand(ast.clone(), &env) {
-                    (true, Ok(new_ast)) => {
-                        ast = new_ast;
-                        continue 'tco;
+                _ => Err(err),
+            }
+        }
+        Frame::Try {
+            catches,
+            finally,
+            env,
+        } => {
+            let res = match find_catch(&err, &catches, &env) {
+                Ok(Some((body, catch_env))) => {
+                    if finally.is_some() {
+                        stack.push(Frame::Try {
+                            catches: vec![],
+                            finally,
+                            env,
+                        })?;
                     }
-                    (_, Err(e)) => return Err(e),
-                    _ => (),
+                    return Ok(Eval(body, catch_env, None));
+                }
+                Ok(None) => Err(err),
+                Err(e) => Err(e),
+            };
+            match finally {
+                Some(body) => {
+                    stack.push(Frame::Finally(res))?;
+                    Ok(Eval(body, env, None))
                 }
+                None => res.map(Return),
+            }
+        }
+        _ => Err(err),
+    }
+}
+
+// Runs the evaluator until the stack is empty
+fn run(mut control: Control, stack: &mut Stack) -> MalRet {
+    let _depth = match limits::enter() {
+        Ok(depth) => Some(depth),
+        Err(e) => {
+            control = Throw(e);
+            None
+        }
+    };
+    loop {
+        let next = match control {
+            Eval(ast, env, tail) => limits::tick().and_then(|_| {
+                gc::maybe_collect();
+                eval_form(ast, env, tail, stack)
+            }),
+            Return(v) => match stack.pop() {
+                Some(frame) => resume(frame, v, stack),
+                None => return Ok(v),
+            },
+            Throw(e) => match stack.pop() {
+                Some(frame) => unwind(frame, e, stack),
+                None => return Err(e),
+            },
+        };
+        control = next.unwrap_or_else(Throw);
+    }
+}
+
+fn eval(ast: Value, env: Env) -> MalRet {
+    run(Eval(ast, env, None), &mut Stack(vec![]))
+}
+
+// Calls a continuation from native code, see Value::apply
+fn resume_cont(k: &Continuation, v: Value) -> MalRet {
+    let mut stack = Stack(vec![]);
+    let control = match stack.reinstate(k) {
+        Ok(()) => Return(v),
+        Err(e) => Throw(e),
+    };
+    run(control, &mut stack)
+}
+
+// Evaluates a symbol or the elements of a collection
+fn eval_ast(ast: Value, env: Env, stack: &mut Stack) -> Result<Control, MalErr> {
+    let (ast, meta) = match ast {
+        Value::Meta(v, meta) => (*v, Some(*meta)),
+        ast => (ast, None),
+    };
+    match ast {
+        Symbol(_) => Ok(Return(env_get(&env, &ast)?)),
+        List(l) => eval_seq(SeqKind::List(meta), l.into_iter().collect(), env, stack),
+        Value::Vec(v) => eval_seq(SeqKind::Vec(meta), v, env, stack),
+        Map(hm) => {
+            let (keys, vals) = hm.into_iter().unzip();
+            eval_seq(SeqKind::Map(keys, meta), vals, env, stack)
+        }
+        _ => Ok(Return(ast)),
+    }
+}
 
-                if l.len() == 0 {
-                    return Ok(ast);
+// Takes one step evaluating the form
+fn eval_form(ast: Value, env: Env, tail: Tail, stack: &mut Stack) -> Result<Control, MalErr> {
+    let ast = match ast {
+        List(ref l) if l.is_empty() => return Ok(Return(ast)),
+        List(_) => match macroexpand(ast, &env) {
+            (true, Ok(new_ast)) => return Ok(Eval(new_ast, env, tail)),
+            (_, Err(e)) => return Err(e),
+            (false, Ok(same)) => same,
+        },
+        _ => return eval_ast(ast, env, stack),
+    };
+    let l = match ast.seq() {
+        Some(l) if !l.is_empty() => l,
+        _ => return eval_ast(ast, env, stack),
+    };
+    let a0 = &l[0];
+    let ret = match a0 {
+        Symbol(ref a0sym) if a0sym == "def!" => {
+            let (sym, meta) = def_target(&l[1])?;
+            let form = l[2].clone();
+            stack.push(Frame::Def {
+                sym,
+                meta,
+                form: ast,
+                env: env.clone(),
+            })?;
+            return Ok(Eval(form, env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "defmulti" => {
+            // (defmulti name dispatch-fn :default value)
+            let name = match (&l.get(1), &env.ns) {
+                (Some(Symbol(ref s)), Some(ns)) => format!("{}/{}", ns, s),
+                _ => return error("defmulti: expected a name, at the top level"),
+            };
+            let dispatch = eval(l[2].clone(), env.clone())?;
+            let default = match l.get(3) {
+                Some(k) if k == &keyword("default") && l.len() == 5 => {
+                    eval(l[4].clone(), env.clone())?
                 }
-                let a0 = &l[0];
-                match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
-                    }
-                    Sym(ref a0sym) if a0sym == "let*" => {
-                        env = env_new(Some(env.clone()));
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
-                                }
-                            }
-                            _ => {
-                                return error("let* with non-List bindings");
-                            }
-                        };
-                        ast = a2;
-                        continue 'tco;
+                None => keyword("default"),
+                _ => return error("defmulti: the only option is :default"),
+            };
+            defmulti(&name, dispatch, default);
+            // A function calling the method for its arguments, which
+            // remove-method and friends know by its metadata
+            env_set(
+                &env,
+                l[1].clone(),
+                dispatch_fn("multifn-dispatch", "multi", &name)?,
+            )
+        }
+        Symbol(ref a0sym) if a0sym == "defmethod" => {
+            // (defmethod name dispatch-value [params] body...)
+            if l.len() < 4 {
+                return error("defmethod: expected a name, a dispatch value and [params]");
+            }
+            let name = match resolve_var(&env, &l[1]) {
+                Some(name) => name,
+                None => return error("defmethod: no such multimethod"),
+            };
+            let dispatch_val = eval(l[2].clone(), env.clone())?;
+            let mut f = vec![sym("fn*"), l[3].clone()];
+            f.push(do_form(&l[4..]));
+            defmethod(&name, dispatch_val, eval(list(f), env.clone())?)
+        }
+        Symbol(ref a0sym) if a0sym == "defprotocol" => {
+            // (defprotocol Name "doc" (method [this ...] "doc")...)
+            let ns = match (l.get(1), &env.ns) {
+                (Some(Symbol(_)), Some(ns)) => ns.clone(),
+                _ => return error("defprotocol: expected a name, at the top level"),
+            };
+            let mut methods = vec![];
+            for sig in l[2..].iter() {
+                match sig {
+                    Value::String(_) => (),
+                    List(ref m) if matches!(m.front(), Some(Symbol(_))) => {
+                        let method = format!("{}/{}", ns, m[0].pr_str(false));
+                        let f = dispatch_fn("protocol-dispatch", "protocol-method", &method)?;
+                        env_set(&env, m[0].clone(), f)?;
+                        methods.push(method);
                     }
-                    Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
-                    Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
-                        continue 'tco;
+                    _ => return error("defprotocol: expected (method [this ...]) signatures"),
+                }
+            }
+            let name = format!("{}/{}", ns, l[1].pr_str(false));
+            defprotocol(&name, methods);
+            env_set(&env, l[1].clone(), Symbol(name))
+        }
+        Symbol(ref a0sym) if a0sym == "extend-type" => {
+            // (extend-type Type Protocol (method [this ...] body...)... Protocol ...)
+            if l.len() < 2 {
+                return error("extend-type: expected a type");
+            }
+            let t = type_key(&env, &l[1])?;
+            let mut protocol = None;
+            for form in l[2..].iter() {
+                match (form, &protocol) {
+                    (Symbol(_), _) => protocol = Some(protocol_name(&env, form)?),
+                    (List(_), Some(ref p)) => {
+                        add_impl(&env, p, &t, &form.seq().unwrap())?;
                     }
-                    Sym(ref a0sym) if a0sym == "defmacro!" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        let r = eval(a2, env.clone())?;
-                        match r {
-                            MalFunc {
-                                eval,
-                                ast,
-                                env,
-                                params,
-                                ..
-                            } => Ok(env_set(
-                                &env,
-                                a1.clone(),
-                                MalFunc {
-                                    eval: eval,
-                                    ast: ast.clone(),
-                                    env: env.clone(),
-                                    params: params.clone(),
-                                    is_macro: true,
-                                    meta: Rc::new(Nil),
-                                },
-                            )?),
-                            _ => error("set_macro on non-function"),
-                        }
+                    _ => return error("extend-type: expected a protocol and methods"),
+                }
+            }
+            Ok(Null)
+        }
+        Symbol(ref a0sym) if a0sym == "extend-protocol" => {
+            // (extend-protocol Protocol Type (method [this ...] body...)... Type ...)
+            if l.len() < 2 {
+                return error("extend-protocol: expected a protocol");
+            }
+            let protocol = protocol_name(&env, &l[1])?;
+            let mut t = None;
+            for form in l[2..].iter() {
+                match (form, &t) {
+                    (Symbol(_), _) => t = Some(type_key(&env, form)?),
+                    (List(_), Some(ref t)) => {
+                        add_impl(&env, &protocol, t, &form.seq().unwrap())?;
                     }
-                    Sym(ref a0sym) if a0sym == "macroexpand" => {
-                        match macroexpand(l[1].clone(), &env) {
-                            (_, Ok(new_ast)) => Ok(new_ast),
-                            (_, e) => return e,
+                    _ => return error("extend-protocol: expected a type and methods"),
+                }
+            }
+            Ok(Null)
+        }
+        Symbol(ref a0sym) if a0sym == "defrecord" => {
+            // (defrecord Name [fields] Protocol (method [this ...] body...)...)
+            let (name, ns) = match (l.get(1), &env.ns) {
+                (Some(Symbol(ref s)), Some(ns)) => (s.clone(), ns.clone()),
+                _ => return error("defrecord: expected a name, at the top level"),
+            };
+            let fields = match l.get(2) {
+                Some(Value::Vec(ref f)) if f.iter().all(|f| matches!(f, Symbol(_))) => f.clone(),
+                _ => return error("defrecord: expected a vector of field names"),
+            };
+            let t = format!("{}/{}", ns, name);
+            let kw = |f: &Value| keyword(&f.pr_str(false));
+            let quoted_type = list(vec![sym("quote"), Symbol(t.clone())]);
+
+            // (->Name x y) and (map->Name {:x x :y y})
+            let mut kvs = vec![sym("hash-map")];
+            for f in fields.iter() {
+                kvs.push(kw(f));
+                kvs.push(f.clone());
+            }
+            let ctor = list(vec![
+                sym("fn*"),
+                Value::Vec(fields.to_vec()),
+                list(vec![sym("make-record"), quoted_type.clone(), list(kvs)]),
+            ]);
+            let from_map = list(vec![
+                sym("fn*"),
+                Value::Vec(vec![sym("m")]),
+                list(vec![sym("make-record"), quoted_type, sym("m")]),
+            ]);
+            env_sets(&env, &format!("->{}", name), eval(ctor, ns_env(CORE_NS))?);
+            env_sets(
+                &env,
+                &format!("map->{}", name),
+                eval(from_map, ns_env(CORE_NS))?,
+            );
+            env_set(&env, l[1].clone(), Symbol(t.clone()))?;
+
+            // The methods given inline see the fields as locals
+            let mut protocol = None;
+            for form in l[3..].iter() {
+                match (form, &protocol) {
+                    (Symbol(_), _) => protocol = Some(protocol_name(&env, form)?),
+                    (List(ref m), Some(ref p)) => {
+                        let this = match m.get(1) {
+                            Some(Value::Vec(ref params)) if !params.is_empty() => params[0].clone(),
+                            _ => return error("defrecord: methods take [this ...]"),
+                        };
+                        let mut binds = vec![];
+                        for f in fields.iter() {
+                            binds.push(f.clone());
+                            binds.push(list(vec![sym("get"), this.clone(), kw(f)]));
                         }
+                        let m = form.seq().unwrap();
+                        let body = list(vec![sym("let*"), Value::Vec(binds), do_form(&m[2..])]);
+                        add_impl(&env, p, &t, &[m[0].clone(), m[1].clone(), body])?;
                     }
-                    Sym(ref a0sym) if a0sym == "try*" => match eval(l[1].clone(), env.clone()) {
-                        Err(ref e) if l.len() >= 3 => {
-                            let exc = match e {
-                                ErrMalVal(mv) => mv.clone(),
-                                ErrString(s) => Str(s.to_string()),
-                            };
-                            match l[2].clone() {
-                                List(c, _) => {
-                                    let catch_env = env_bind(
-                                        Some(env.clone()),
-                                        list!(vec![c[1].clone()]),
-                                        vec![exc],
-                                    )?;
-                                    eval(c[2].clone(), catch_env)
-                                }
-                                _ => error("invalid catch block"),
-                            }
-                        }
-                        res => res,
-                    },
-                    Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
-                            List(_, _) => {
-                                ast = l.last().unwrap_or(&Nil).clone();
-                                continue 'tco;
-                            }
-                            _ => error("invalid do form"),
-                        }
+                    _ => return error("defrecord: expected a protocol and methods"),
+                }
+            }
+            Ok(Symbol(t))
+        }
+        Symbol(ref a0sym) if a0sym == "handler-bind" => {
+            // (handler-bind [type-or-pred handler ...] body...)
+            let binds = match l.get(1) {
+                Some(Value::Vec(ref b)) if b.len() % 2 == 0 => b.clone(),
+                _ => return error("handler-bind: expected a vector of handlers"),
+            };
+            let mut handlers = vec![];
+            for (m, h) in binds.iter().tuples() {
+                handlers.push((eval(m.clone(), env.clone())?, eval(h.clone(), env.clone())?));
+            }
+            // Not a tail call, the handlers are removed once the body is done
+            stack.enter(Frame::Handlers(handlers))?;
+            return Ok(Eval(do_form(&l[2..]), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "restart-case" => {
+            // (restart-case expr (name [params] body...)...)
+            if l.len() < 2 {
+                return error("restart-case: expected an expression and restarts");
+            }
+            let mut names = vec![];
+            for clause in l[2..].iter() {
+                match clause {
+                    List(ref c) if c.len() >= 2 && matches!(c[0], Symbol(_)) => {
+                        names.push(c[0].pr_str(false))
                     }
-                    Sym(ref a0sym) if a0sym == "if" => {
-                        let cond = eval(l[1].clone(), env.clone())?;
-                        match cond {
-                            Bool(false) | Nil if l.len() >= 4 => {
-                                ast = l[3].clone();
-                                continue 'tco;
-                            }
-                            Bool(false) | Nil => Ok(Nil),
-                            _ if l.len() >= 3 => {
-                                ast = l[2].clone();
-                                continue 'tco;
-                            }
-                            _ => Ok(Nil),
-                        }
+                    _ => return error("restart-case: expected (name [params] body...)"),
+                }
+            }
+            let first = restart_ids(names.len());
+            stack.enter(Frame::Restarts {
+                names,
+                first,
+                clauses: l[2..].to_vec(),
+                env: env.clone(),
+            })?;
+            return Ok(Eval(l[1].clone(), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "reset" => {
+            stack.push(Frame::Reset)?;
+            return Ok(Eval(do_form(&l[1..]), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "shift" => {
+            // (shift k body...) takes the rest of the reset's body off the stack
+            // and evaluates body with k bound to it, in place of the reset's body
+            if l.len() < 3 || !matches!(l[1], Symbol(_)) {
+                return error("shift: expected a symbol and a body");
+            }
+            let k = Value::Cont(Shared::new(Continuation {
+                resume: resume_cont,
+                frames: stack.capture()?,
+            }));
+            let env = env_bind(Some(env), &list(vec![l[1].clone()]), vec![k])?;
+            return Ok(Eval(do_form(&l[2..]), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "var" => match l.get(1) {
+            Some(v) if l.len() == 2 => match resolve_var(&env, v) {
+                Some(name) => Ok(Symbol(name)),
+                None => error(&format!("unable to resolve var {}", v.pr_str(true))),
+            },
+            _ => error("var: expected a symbol"),
+        },
+        Symbol(ref a0sym) if a0sym == "binding" => {
+            let binds = match l.get(1).and_then(|b| b.seq()) {
+                Some(b) if b.len() % 2 == 0 => b,
+                Some(_) => return error("binding: expected pairs of a var and a value"),
+                _ => return error("binding: expected a vector of bindings"),
+            };
+            // All the values are computed before any var is bound
+            let (mut names, mut forms) = (vec![], vec![]);
+            for (b, e) in binds.into_iter().tuples() {
+                names.push(dynamic_var(&env, &b)?);
+                forms.push(e);
+            }
+            let kind = SeqKind::Binding(names, do_form(&l[2..]));
+            return eval_seq(kind, forms, env, stack);
+        }
+        Symbol(ref a0sym) if a0sym == "let*" => {
+            let binds = match l[1].seq() {
+                Some(binds) => binds,
+                None => return error("let* with non-List bindings"),
+            };
+            let mut binds: Vec<(Value, Value)> = binds.into_iter().tuples().collect();
+            binds.reverse();
+            return let_next(env_new(Some(env)), binds, l[2].clone(), tail, stack);
+        }
+        Symbol(ref a0sym) if a0sym == "loop*" => {
+            let binds = match l.get(1).and_then(|b| b.seq()) {
+                Some(b) => b,
+                _ => return error("loop*: expected a vector of bindings"),
+            };
+            let mut binds: Vec<(Value, Value)> = binds.into_iter().tuples().collect();
+            binds.reverse();
+            let body = do_form(&l[2..]);
+            return loop_next(env_new(Some(env)), binds, vec![], body, stack);
+        }
+        Symbol(ref a0sym) if a0sym == "recur" => {
+            let target = match tail {
+                Some(target) => target,
+                None => return error("recur outside of loop*"),
+            };
+            if l.len() - 1 != target.syms.len() {
+                return error(&format!(
+                    "recur expects {} arguments, got {}",
+                    target.syms.len(),
+                    l.len() - 1
+                ));
+            }
+            return eval_seq(SeqKind::Recur(target), l[1..].to_vec(), env, stack);
+        }
+        Symbol(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
+        Symbol(ref a0sym) if a0sym == "quasiquoteexpand" => {
+            Ok(quasiquote(&l[1], &mut SyntaxQuote::new(&env)))
+        }
+        Symbol(ref a0sym) if a0sym == "quasiquote" => {
+            let expansion = quasiquote(&l[1], &mut SyntaxQuote::new(&env));
+            return Ok(Eval(expansion, env, tail));
+        }
+        Symbol(ref a0sym) if a0sym == "defmacro!" => {
+            let (a1, a2) = (l[1].clone(), l[2].clone());
+            let r = eval(a2, env.clone())?;
+            match r {
+                Value::Closure(ref c) => Ok(env_set(
+                    &env,
+                    a1.clone(),
+                    Value::Closure(Shared::new(Closure {
+                        eval: c.eval,
+                        params: c.params.clone(),
+                        body: c.body.clone(),
+                        env: c.env.clone(),
+                        is_macro: true,
+                        meta: Null,
+                    })),
+                )?),
+                _ => error("set_macro on non-function"),
+            }
+        }
+        Symbol(ref a0sym) if a0sym == "macroexpand-1" => match l.get(1) {
+            Some(form) if l.len() == 2 => match is_macro_call(form, &env) {
+                Some((mf, args)) => expand_macro(&mf, args),
+                None => Ok(form.clone()),
+            },
+            _ => error("macroexpand-1: expected a form"),
+        },
+        Symbol(ref a0sym) if a0sym == "macroexpand-all" => match l.get(1) {
+            Some(form) if l.len() == 2 => macroexpand_all(form.clone(), &env),
+            _ => error("macroexpand-all: expected a form"),
+        },
+        Symbol(ref a0sym) if a0sym == "macroexpand" => macroexpand(l[1].clone(), &env).1,
+        Symbol(ref a0sym) if a0sym == "try*" => {
+            let mut catches = vec![];
+            let mut finally = None;
+            for clause in l[2..].iter() {
+                match clause {
+                    List(c) if c.len() >= 3 && c[0] == sym("catch*") => {
+                        catches.push(clause.seq().unwrap())
                     }
-                    Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
-                        Ok(MalFunc {
-                            eval: eval,
-                            ast: Rc::new(a2),
-                            env: env,
-                            params: Rc::new(a1),
-                            is_macro: false,
-                            meta: Rc::new(Nil),
-                        })
+                    List(c) if c.front() == Some(&sym("finally*")) => {
+                        finally = Some(do_form(&clause.seq().unwrap()[1..]))
                     }
-                    Sym(ref a0sym) if a0sym == "eval" => {
-                        ast = eval(l[1].clone(), env.clone())?;
-                        while let Some(ref e) = env.clone().outer {
-                            env = e.clone();
+                    _ => return error("invalid catch block"),
+                }
+            }
+            stack.push(Frame::Try {
+                catches,
+                finally,
+                env: env.clone(),
+            })?;
+            return Ok(Eval(l[1].clone(), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "do" => {
+            let mut forms = l[1..].to_vec();
+            forms.reverse();
+            return do_next(forms, env, tail, stack);
+        }
+        Symbol(ref a0sym) if a0sym == "ns" => {
+            let name = match l.get(1) {
+                Some(Symbol(ref s)) => s.clone(),
+                _ => return error("ns: expected a namespace name"),
+            };
+            in_ns(&name);
+            let require = keyword("require");
+            for clause in l[2..].iter() {
+                match clause.seq() {
+                    Some(ref c) if matches!(clause, List(_)) && c.first() == Some(&require) => {
+                        for spec in c[1..].iter() {
+                            loader::require(spec, eval)?;
                         }
-                        continue 'tco;
                     }
-                    _ => match eval_ast(&ast, &env)? {
-                        List(ref el, _) => {
-                            let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
-                            match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
-                                    ast = a.clone();
-                                    continue 'tco;
-                                }
-                                _ => error("attempt to call non-function"),
-                            }
-                        }
-                        _ => error("expected a list"),
-                    },
+                    _ => return error("ns: expected (:require ...) clauses"),
                 }
             }
-            _ => eval_ast(&ast, &env),
-        };
-
-        break;
-    } // end 'tco loop
-
-    ret
+            Ok(Null)
+        }
+        Symbol(ref a0sym) if a0sym == "require" => {
+            for spec in l[1..].iter() {
+                loader::require(&eval(spec.clone(), env.clone())?, eval)?;
+            }
+            Ok(Null)
+        }
+        Symbol(ref a0sym) if a0sym == "if" => {
+            let then = l.get(2).cloned().unwrap_or(Null);
+            let otherwise = l.get(3).cloned().unwrap_or(Null);
+            if let Some(cond) = immediate(&l[1], &env) {
+                let branch = if cond?.truthy() { then } else { otherwise };
+                return Ok(Eval(branch, env, tail));
+            }
+            stack.push(Frame::If {
+                then,
+                otherwise,
+                env: env.clone(),
+                tail,
+            })?;
+            return Ok(Eval(l[1].clone(), env, None));
+        }
+        Symbol(ref a0sym) if a0sym == "fn*" => {
+            let (a1, a2) = (l[1].clone(), l[2].clone());
+            Ok(closure(a1, a2, env))
+        }
+        Symbol(ref a0sym) if a0sym == "eval" => {
+            stack.push(Frame::Eval)?;
+            return Ok(Eval(l[1].clone(), env, None));
+        }
+        _ => return eval_seq(SeqKind::Call, l, env, stack),
+    };
+    ret.map(Return)
 }
 
 // print
-fn print(ast: &MalVal) -> String {
-    ast.pr_str(true)
+fn print(ast: &Value) -> Result<String, MalErr> {
+    realize_printed(std::slice::from_ref(ast))?;
+    Ok(pr_str_pretty(ast, &PrettyConfig::default()))
 }
 
-fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
-    let ast = read(str)?;
+// Returns what to print for the line, nothing when it holds no form
+fn rep(str: &str, env: &Env) -> Result<Option<String>, MalErr> {
+    let ast = match read(str)? {
+        Some(ast) => ast,
+        None => return Ok(None),
+    };
+    check_recur(&ast, env, false, false)?;
     let exp = eval(ast, env.clone())?;
-    Ok(print(&exp))
+    Ok(Some(print(&exp)?))
 }
 
-fn main() {
-    let mut args = std::env::args();
-    let arg1 = args.nth(1);
-
-    // `()` can be used when no completer is required
-    let mut rl = Editor::<()>::new();
-    if rl.load_history(".mal-history").is_err() {
-        eprintln!("No previous history.");
+// (load-file f) evaluates the forms of the file at the top level
+fn load_file(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Value::String(f)) => loader::load_file(f, eval),
+        _ => error("load-file: expected a file name"),
     }
+}
 
-    // core.rs: defined using rust
-    let repl_env = env_new(None);
+// Makes the environment with the core functions and those defined in mal,
+// and returns the one of the user namespace, where programs start out
+pub fn init_env(argv: Vec<String>) -> Env {
+    // core.rs: defined using rust, in the mal.core namespace
+    let repl_env = ns_env(CORE_NS);
     for (k, v) in core::ns() {
         env_sets(&repl_env, k, v);
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
+    env_sets(
+        &repl_env,
+        "*ARGV*",
+        list(argv.into_iter().map(Value::String).collect()),
+    );
+    env_sets(
+        &ns_env("mal.sys"),
+        "memory-stats",
+        func(memory::memory_stats),
+    );
 
     // core.mal: defined using the language itself
     let _ = rep("(def! *host-language* \"rust\")", &repl_env);
     let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
+    let _ = rep("(def! ^:dynamic *print-length* nil)", &repl_env);
+    let _ = rep("(def! ^:dynamic *print-level* nil)", &repl_env);
+    env_sets(&repl_env, "load-file", func(load_file));
+    // #my/tag form is read as (tagged-literal "my/tag" (quote form)). The
+    // readers are looked up in the current namespace, so that one defining
+    // its own *data-readers*, or binding this one, registers its tags.
+    let _ = rep("(def! ^:dynamic *data-readers* {})", &repl_env);
+    let _ = rep("(def! tagged-literal (fn* (tag form) (let* (f (get (eval (quote *data-readers*)) tag)) (if f (f form) (throw (str \"no reader function for tag #\" tag))))))", &repl_env);
+    // Vars are named by their qualified symbol, (meta (var f)) gives their metadata
+    let _ = rep("(defmacro! defn (fn* (name & decl) (let* [doc (if (string? (first decl)) (first decl)) sig (if doc (rest decl) decl)] `(def! (with-meta ~name ~(hash-map :doc doc :arglists (list (first sig)) :source (cons 'defn (cons name decl)))) (fn* ~(first sig) (do ~@(rest sig)))))))", &repl_env);
+    let _ = rep("(defmacro! doc (fn* (name) `(let* [m (meta (var ~name))] (do (println \"-------------------------\") (println (str (get m :ns) \"/\" (get m :name))) (if (get m :arglists) (apply prn (get m :arglists))) (if (get m :doc) (println \" \" (get m :doc))) nil))))", &repl_env);
+    let _ = rep("(defmacro! source (fn* (name) `(println (pr-str-pretty (get (meta (var ~name)) :source)))))", &repl_env);
+    let _ = rep(
+        "(def! use-value (fn* (v) (invoke-restart 'use-value v)))",
+        &repl_env,
+    );
+    // Lazy seqs, see LazySeq
+    let _ = rep(
+        "(defmacro! lazy-seq (fn* (& body) `(lazy-seq* (fn* () (do ~@body)))))",
+        &repl_env,
+    );
+    let _ = rep("(def! take (fn* (n coll) (lazy-seq (if (> n 0) (if (empty? coll) nil (cons (first coll) (take (- n 1) (rest coll))))))))", &repl_env);
+    // Coroutines and generators, built on reset and shift. A coroutine is an
+    // atom holding the function that starts it, the continuation of its last
+    // yield, or whether it is running or done.
+    let _ = rep("(def! ^:dynamic *current-coroutine* nil)", &repl_env);
+    let _ = rep("(def! yield (fn* (& v) (if (nil? *current-coroutine*) (throw \"yield: not in a coroutine\") (shift k (do (reset! *current-coroutine* {:k k}) (first v))))))", &repl_env);
+    let _ = rep("(defmacro! coroutine (fn* (params & body) `(atom (hash-map :start (fn* ~params (reset (do ~@body)))))))", &repl_env);
+    let _ = rep("(def! resume (fn* (co & args) (let* [state @co] (if (contains? state :done) (throw \"resume: the coroutine is done\") (if (contains? state :running) (throw \"resume: the coroutine is running\") (binding [*current-coroutine* co] (do (reset! co {:running true}) (let* [r (if (contains? state :k) ((get state :k) (first args)) (apply (get state :start) args))] (do (if (contains? @co :running) (reset! co {:done true})) r))))))))))", &repl_env);
+    let _ = rep("(def! done? (fn* (co) (contains? @co :done)))", &repl_env);
+    let _ = rep("(def! gen-seq (fn* (co) (lazy-seq (let* [v (resume co)] (if (done? co) nil (cons v (gen-seq co)))))))", &repl_env);
+    let _ = rep(
+        "(defmacro! generator (fn* (& body) `(gen-seq (coroutine [] ~@body))))",
+        &repl_env,
+    );
+    let _ = rep(
+        "(defmacro! dosync (fn* (& body) `(dosync* (fn* () (do ~@body)))))",
+        &repl_env,
+    );
+    // go blocks and channels, see core.rs
     let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
+        "(defmacro! go (fn* (& body) `(go* (fn* () (reset (do ~@body))))))",
+        &repl_env,
+    );
+    let _ = rep(
+        "(def! <! (fn* (ch) (do (check-go \"<!\") (shift k (chan-take ch k)))))",
+        &repl_env,
+    );
+    let _ = rep(
+        "(def! >! (fn* (ch v) (do (check-go \">!\") (shift k (chan-put ch v k)))))",
+        &repl_env,
+    );
+    let _ = rep(
+        "(def! alts! (fn* (ops) (do (check-go \"alts!\") (shift k (chan-alts ops k)))))",
+        &repl_env,
+    );
+    #[cfg(feature = "threads")]
+    let _ = rep(
+        "(defmacro! future (fn* (& body) `(future-call (fn* () (do ~@body)))))",
         &repl_env,
     );
     let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
 
+    // Everything else starts out in the user namespace
+    in_ns("user")
+}
+
+fn main() {
+    // -I and --load-path add directories to look for modules in,
+    // --memory-stats prints what the program holds at exit, and --fuel,
+    // --time-limit (in milliseconds), --depth-limit and --size-limit set the
+    // limits of each line or of the file, see limits.rs
+    let mut args = std::env::args().skip(1).peekable();
+    let mut load_path = vec![];
+    let mut memory_stats = false;
+    let mut limits = Limits::default();
+    while let Some(flag) = args.next_if(|a| {
+        [
+            "-I",
+            "--load-path",
+            "--memory-stats",
+            "--fuel",
+            "--time-limit",
+            "--depth-limit",
+            "--size-limit",
+        ]
+        .contains(&a.as_str())
+    }) {
+        if flag == "--memory-stats" {
+            memory_stats = true;
+            continue;
+        }
+        let value = match args.next() {
+            Some(value) => value,
+            None => {
+                eprintln!("{} needs a value", flag);
+                std::process::exit(1);
+            }
+        };
+        if flag == "-I" || flag == "--load-path" {
+            load_path.push(value.into());
+            continue;
+        }
+        let n: u64 = match value.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("{} needs a number", flag);
+                std::process::exit(1);
+            }
+        };
+        match flag.as_str() {
+            "--fuel" => limits.fuel = Some(n),
+            "--time-limit" => limits.time = Some(Duration::from_millis(n)),
+            "--depth-limit" => limits.depth = Some(n as usize),
+            _ => limits.size = Some(n as usize),
+        }
+    }
+    loader::init_load_path(load_path);
+    let arg1 = args.next();
+
+    let mut rl = DefaultEditor::new().unwrap();
+    #[cfg(feature = "with-file-history")]
+    if rl.load_history(".mal-history").is_err() {
+        eprintln!("No previous history.");
+    }
+
+    let repl_env = init_env(args.collect());
+
     // Invoked with arguments
     if let Some(f) = arg1 {
+        set_limits(limits);
         match rep(&format!("(load-file \"{}\")", f), &repl_env) {
-            Ok(_) => std::process::exit(0),
+            Ok(_) => match core::run_scheduler(true) {
+                Ok(()) => exit(0, memory_stats),
+                Err(e) => {
+                    println!("Error: {}", format_error(e));
+                    exit(1, memory_stats)
+                }
+            },
             Err(e) => {
                 println!("Error: {}", format_error(e));
-                std::process::exit(1);
+                exit(1, memory_stats)
             }
         }
     }
@@ -368,13 +1574,20 @@ fn main() {
         let readline = rl.readline("user> ");
         match readline {
             Ok(line) => {
-                rl.add_history_entry(&line);
-                rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
-                    match rep(&line, &repl_env) {
-                        Ok(out) => println!("{}", out),
+                let _ = rl.add_history_entry(&line);
+                #[cfg(feature = "with-file-history")]
+                let _ = rl.save_history(".mal-history");
+                if !line.is_empty() {
+                    set_limits(limits.clone());
+                    match rep(&line, &ns_env(&current_ns())) {
+                        Ok(Some(out)) => println!("{}", out),
+                        Ok(None) => (),
                         Err(e) => println!("Error: {}", format_error(e)),
                     }
+                    // Let the go blocks the line started run
+                    if let Err(e) = core::run_scheduler(false) {
+                        println!("Error: {}", format_error(e));
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => continue,
@@ -385,4 +1598,117 @@ fn main() {
             }
         }
     }
+    exit(0, memory_stats)
+}
+
+fn exit(code: i32, memory_stats: bool) -> ! {
+    if memory_stats {
+        eprintln!("{}", memory::summary());
+    }
+    std::process::exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc::tests::lock_registries;
+
+    // Evaluates the forms of the source one after the other in a fresh
+    // environment, and returns the value of the last one
+    fn eval_src(src: &str) -> MalRet {
+        let env = init_env(vec![]);
+        match read(&format!("(do {}\n)", src))? {
+            Some(ast) => {
+                check_recur(&ast, &env, false, false)?;
+                eval(ast, env)
+            }
+            None => Ok(Null),
+        }
+    }
+
+    #[test]
+    fn test_load_file_reports_every_syntax_error() {
+        let _registries = lock_registries();
+        let path = std::env::temp_dir().join("mal-test-load-file.mal");
+        std::fs::write(&path, "(def! loaded 1)\n(a ])\n(b").unwrap();
+        let res = eval_src(&format!("(load-file {:?})", path.display().to_string()));
+        let _ = std::fs::remove_file(&path);
+        match res {
+            Err(ref e) => {
+                let message = format_error(e.clone());
+                assert!(message.contains("line 2"), "{}", message);
+                assert!(message.contains("line 3"), "{}", message);
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_print_length_bounds_lazy_seqs() {
+        let _registries = lock_registries();
+        assert_eq!(
+            eval_src(
+                "(def! xs (lazy-seq (cons 1 xs)))
+                 (binding [*print-length* 3] (pr-str xs))"
+            ),
+            Ok(Value::String("(1 1 1 ...)".to_string()))
+        );
+        // Only mal.core's *print-length* limits printing, not the user's
+        assert_eq!(
+            eval_src("(def! *print-length* 1) (pr-str [1 2 3])"),
+            Ok(Value::String("[1 2 3]".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_data_readers_from_user_namespace() {
+        let _registries = lock_registries();
+        assert_eq!(
+            eval_src("(binding [*data-readers* {\"my/first\" first}] #my/first [1 2 3])"),
+            Ok(Value::Number(1.0))
+        );
+        // The user namespace's own *data-readers* hides mal.core's
+        assert_eq!(
+            eval_src("(def! *data-readers* {\"my/len\" count}) #my/len [1 2 3]"),
+            Ok(Value::Number(3.0))
+        );
+    }
+
+    #[test]
+    fn test_plain_catch_binds_the_message() {
+        let _registries = lock_registries();
+        let message = Ok(Value::String("'nope' not found".to_string()));
+        assert_eq!(eval_src("(try* (nope) (catch* e e))"), message);
+        assert_eq!(
+            eval_src("(try* (nope) (catch* :error e (get e :message)))"),
+            message
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "threads")]
+    fn test_ns_is_the_current_namespace_of_the_thread() {
+        let _registries = lock_registries();
+        let ns = Value::Symbol("ns-test".to_string());
+        assert_eq!(
+            eval_src("(in-ns (quote ns-test)) [*ns* @(future *ns*)]"),
+            Ok(Value::Vec(vec![ns.clone(), ns]))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "counting-allocator")]
+    fn test_self_referencing_closures_stay_bounded() {
+        let _registries = lock_registries();
+        // Each closure is held by the env it closes over. Left uncollected,
+        // a round would keep megabytes of them.
+        let src = "(def! churn (fn* (n) (loop* [i 0] (if (< i n) (do (let* [f (fn* () f)] f) (recur (+ i 1))) nil))))
+                   (def! in-use (fn* () (get (mal.sys/memory-stats) :bytes-in-use)))
+                   (churn 5000)
+                   (let* [before (in-use)] (do (churn 5000) (churn 5000) (churn 5000) (- (in-use) before)))";
+        match eval_src(src) {
+            Ok(Value::Number(grown)) => assert!(grown < 1_000_000.0, "{} more bytes in use", grown),
+            res => panic!("{:?}", res),
+        }
+    }
 }
@@ -0,0 +1,87 @@
+// The pointer and cell types that mal values and envs are built from. By
+// default they are Rc and RefCell, so values stay on one thread and cost
+// nothing extra. With the "threads" feature they are Arc and a lock, so
+// values can be sent to other threads for future, pmap and pcalls.
+
+#[cfg(not(feature = "threads"))]
+pub use std::cell::RefCell as Lock;
+#[cfg(not(feature = "threads"))]
+pub use std::rc::Rc as Shared;
+#[cfg(not(feature = "threads"))]
+pub use std::rc::Weak;
+
+#[cfg(feature = "threads")]
+pub use self::threads::Lock;
+#[cfg(feature = "threads")]
+pub use std::sync::Arc as Shared;
+#[cfg(feature = "threads")]
+pub use std::sync::Weak;
+
+// Declares state that all the threads running mal code share, such as the
+// namespaces. Without the "threads" feature only one thread runs mal code, so
+// these are thread locals. Either way they are used through `with`. The
+// crate roots declare this module with #[macro_use] before the others.
+#[cfg(not(feature = "threads"))]
+macro_rules! global {
+    ($($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr;)*) => {
+        thread_local! { $($(#[$attr])* static $name: $t = $init;)* }
+    };
+}
+
+#[cfg(feature = "threads")]
+macro_rules! global {
+    ($($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr;)*) => {
+        $($(#[$attr])* static $name: $crate::sync::Global<$t> = $crate::sync::Global::new(|| $init);)*
+    };
+}
+
+#[cfg(feature = "threads")]
+pub use self::threads::Global;
+
+#[cfg(feature = "threads")]
+mod threads {
+    use std::fmt;
+    use std::sync::{LazyLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+    // A RwLock with the borrow methods of RefCell, so the code using it does
+    // not depend on the feature. A poisoned lock is still used, the thread
+    // that panicked already reported it.
+    pub struct Lock<T>(RwLock<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(v: T) -> Lock<T> {
+            Lock(RwLock::new(v))
+        }
+
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(|e| e.into_inner())
+        }
+
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(|e| e.into_inner())
+        }
+
+        pub fn replace(&self, v: T) -> T {
+            std::mem::replace(&mut *self.borrow_mut(), v)
+        }
+    }
+
+    // A static made on first use, with the `with` of a thread local
+    pub struct Global<T>(LazyLock<T>);
+
+    impl<T> Global<T> {
+        pub const fn new(init: fn() -> T) -> Global<T> {
+            Global(LazyLock::new(init))
+        }
+
+        pub fn with<R, F: FnOnce(&T) -> R>(&'static self, f: F) -> R {
+            f(&self.0)
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for Lock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.borrow().fmt(f)
+        }
+    }
+}
@@ -1,21 +1,639 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::fmt::Display;
 
 extern crate thiserror;
 use self::thiserror::Error;
-use crate::reader::TokenType;
+use crate::cont::Frame;
+use crate::env::{env_bind, Env};
+use crate::printer::{map_key, pr_str};
+use crate::reader::{read_str, TokenType};
+use crate::sync::{Lock, Shared};
+
+// A mutable reference cell. Atoms are compared by identity, and their Debug
+// output does not look inside them, since an atom may hold itself.
+#[derive(Clone)]
+pub struct Atom(pub Shared<AtomCell>);
+
+// The value of an atom, which it derefs to, with the watches and the
+// validator added to the atom. Every change goes through core::set_atom.
+pub struct AtomCell {
+    value: Lock<Value>,
+    // The watches as key and function, in the order added
+    pub watches: Lock<Vec<(Value, Value)>>,
+    // The function checking new values, or nil
+    pub validator: Lock<Value>,
+}
+
+impl Atom {
+    pub fn new(v: Value) -> Atom {
+        Atom(Shared::new(AtomCell {
+            value: Lock::new(v),
+            watches: Lock::new(vec![]),
+            validator: Lock::new(Value::Null),
+        }))
+    }
+}
+
+impl std::ops::Deref for AtomCell {
+    type Target = Lock<Value>;
+
+    fn deref(&self) -> &Lock<Value> {
+        &self.value
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Atom) -> bool {
+        Shared::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Atom({:p})", Shared::as_ptr(&self.0))
+    }
+}
+
+pub type MalArgs = Vec<Value>;
+pub type MalRet = Result<Value, MalErr>;
+
+// A function written in Rust, with the metadata given to it by with-meta
+#[derive(Clone)]
+pub struct Builtin {
+    pub f: fn(MalArgs) -> MalRet,
+    pub meta: Box<Value>,
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Builtin) -> bool {
+        std::ptr::fn_addr_eq(self.f, other.f) && self.meta == other.meta
+    }
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Builtin({:p})", self.f as *const ())
+    }
+}
+
+// A function made by fn*, or a macro made from one by defmacro!. Closures
+// are compared by identity, and their Debug output leaves out the env, which
+// may hold the closure itself.
+pub struct Closure {
+    // The evaluator of the step that made the closure, which runs the body
+    pub eval: fn(Value, Env) -> MalRet,
+    pub params: Value,
+    pub body: Value,
+    pub env: Env,
+    pub is_macro: bool,
+    pub meta: Value,
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closure({} {})", self.params, self.body)
+    }
+}
+
+// A continuation captured by shift, the frames of the evaluator's stack
+// between the shift and its reset, outermost first. Calling it runs them
+// again with the argument as the value of the shift. Continuations are
+// compared by identity.
+pub struct Continuation {
+    // The evaluator of the step that captured it, which runs the frames
+    pub resume: fn(&Continuation, Value) -> MalRet,
+    pub frames: Vec<Frame>,
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Continuation) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Continuation({} frames)", self.frames.len())
+    }
+}
+
+// A lazy seq, made by lazy-seq or generator. It holds a function of no
+// arguments giving the seq until something looks into it, and from then on
+// its first element and the rest, or nothing. Lazy seqs are compared by
+// identity, = compares their elements.
+pub struct LazySeq(pub Lock<Lazy>);
+
+pub enum Lazy {
+    Thunk(Value),
+    Cons(Value, Value),
+    Empty,
+}
+
+impl LazySeq {
+    // Returns the first element and the rest, calling the function the
+    // first time
+    pub fn realize(&self) -> Result<Option<(Value, Value)>, MalErr> {
+        let thunk = match *self.0.borrow() {
+            Lazy::Cons(ref first, ref rest) => return Ok(Some((first.clone(), rest.clone()))),
+            Lazy::Empty => return Ok(None),
+            Lazy::Thunk(ref f) => f.clone(),
+        };
+        // The function runs without the borrow, it may look into other seqs
+        let cell = thunk.apply(vec![])?.uncons()?;
+        *self.0.borrow_mut() = match cell {
+            Some((ref first, ref rest)) => Lazy::Cons(first.clone(), rest.clone()),
+            None => Lazy::Empty,
+        };
+        Ok(cell)
+    }
+
+    // Returns the first element and the rest if the seq is realized and not
+    // empty, None if it is empty, or nothing if it is not realized
+    fn peek(&self) -> Option<Option<(Value, Value)>> {
+        match *self.0.borrow() {
+            Lazy::Cons(ref first, ref rest) => Some(Some((first.clone(), rest.clone()))),
+            Lazy::Empty => Some(None),
+            Lazy::Thunk(_) => None,
+        }
+    }
+
+    // Returns the elements realized so far, at most `limit` of them, and
+    // whether there are more. Without a limit, a seq realized into a cycle,
+    // like the one of (def! xs (lazy-seq (cons 1 xs))), gives each element
+    // once and has more.
+    pub fn realized(&self, limit: Option<usize>) -> (Vec<Value>, bool) {
+        let mut elems = vec![];
+        let mut seen = HashSet::new();
+        seen.insert(self as *const LazySeq);
+        let mut cell = self.peek();
+        loop {
+            let (first, rest) = match cell {
+                Some(Some(cons)) if limit != Some(elems.len()) => cons,
+                Some(None) => return (elems, false),
+                _ => return (elems, true),
+            };
+            elems.push(first);
+            cell = match rest {
+                Value::Lazy(ref s) if limit.is_none() && !seen.insert(Shared::as_ptr(s)) => {
+                    return (elems, true)
+                }
+                Value::Lazy(ref s) => s.peek(),
+                rest => {
+                    let mut tail = rest.seq().unwrap_or_default();
+                    let room = limit.map_or(tail.len(), |n| n - elems.len());
+                    let more = tail.len() > room;
+                    tail.truncate(room);
+                    elems.extend(tail);
+                    return (elems, more);
+                }
+            }
+        }
+    }
+}
+
+// Drops a long realized seq one cell at a time, rather than recursing once
+// per cell
+impl Drop for LazySeq {
+    fn drop(&mut self) {
+        let mut next = self.0.replace(Lazy::Empty);
+        while let Lazy::Cons(_, Value::Lazy(rest)) = next {
+            next = match Shared::try_unwrap(rest) {
+                Ok(rest) => rest.0.replace(Lazy::Empty),
+                Err(_) => return,
+            };
+        }
+    }
+}
+
+impl PartialEq for LazySeq {
+    fn eq(&self, other: &LazySeq) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for LazySeq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LazySeq({:p})", self)
+    }
+}
+
+// A channel of go blocks, see the scheduler in core.rs. Channels are
+// compared by identity.
+pub struct Channel(pub Lock<ChanState>);
+
+pub struct ChanState {
+    pub buf: VecDeque<Value>,
+    pub cap: usize,
+    pub takers: VecDeque<Waiter>,
+    pub putters: VecDeque<(Value, Waiter)>,
+    pub closed: bool,
+}
+
+// A parked go block: its continuation, the channel its result goes to, and
+// for alts! the flag set once one of its operations completed and the
+// channel of this operation
+pub struct Waiter {
+    pub k: Value,
+    pub result: Value,
+    pub alt: Option<Shared<Lock<bool>>>,
+    pub port: Value,
+}
+
+impl Channel {
+    pub fn new(cap: usize) -> Channel {
+        Channel(Lock::new(ChanState {
+            buf: VecDeque::new(),
+            cap,
+            takers: VecDeque::new(),
+            putters: VecDeque::new(),
+            closed: false,
+        }))
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Channel) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Channel({:p})", self)
+    }
+}
+
+// The result of a future, filled in by the thread running it. Futures are
+// compared by identity.
+#[cfg(feature = "threads")]
+pub struct Future {
+    result: std::sync::Mutex<Option<MalRet>>,
+    done: std::sync::Condvar,
+}
+
+#[cfg(feature = "threads")]
+impl Future {
+    pub fn new() -> Future {
+        Future {
+            result: std::sync::Mutex::new(None),
+            done: std::sync::Condvar::new(),
+        }
+    }
+
+    pub fn finish(&self, res: MalRet) {
+        *self.result.lock().unwrap_or_else(|e| e.into_inner()) = Some(res);
+        self.done.notify_all();
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.result
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    // Waits for the result
+    pub fn wait(&self) -> MalRet {
+        let mut result = self.result.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match *result {
+                Some(ref res) => return res.clone(),
+                None => result = self.done.wait(result).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "threads")]
+impl PartialEq for Future {
+    fn eq(&self, other: &Future) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[cfg(feature = "threads")]
+impl fmt::Debug for Future {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Future({:p})", self)
+    }
+}
+
 #[derive(Error, Debug, PartialEq, Clone)]
 pub enum Value {
     // TODO distinguish between integer and float
     Number(f64),
+    // Keywords are strings starting with KEYWORD_PREFIX
     String(String),
+    Char(char),
     Boolean(bool),
     List(VecDeque<Value>),
     Vec(Vec<Value>),
+    // Keyed by the keys printed readably, see map_key
     Map(HashMap<String, Value>),
     Symbol(String),
     Keyword(TokenType),
+    Atom(Atom),
+    // A ref of dosync, holding an atom with its version and value, see core.rs
+    Ref(Atom),
+    Func(Builtin),
+    Closure(Shared<Closure>),
+    Cont(Shared<Continuation>),
+    Lazy(Shared<LazySeq>),
+    Chan(Shared<Channel>),
+    #[cfg(feature = "threads")]
+    Future(Shared<Future>),
+    // A list, vector or map with the metadata given to it by with-meta
+    Meta(Box<Value>, Box<Value>),
     Null,
     Error(String),
 }
+
+// What the strings standing for keywords start with, so that :foo is a
+// string that prints as :foo and is never equal to "foo"
+pub const KEYWORD_PREFIX: &str = "\u{29e}";
+
+// The errors evaluating mal code may end with
+#[derive(Debug, PartialEq, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum MalErr {
+    // An error of the evaluator or of a native function
+    ErrString(String),
+    // A value thrown with throw
+    ErrMalVal(Value),
+    // Going over one of the limits, see limits.rs, which try* does not catch
+    ErrLimit(String),
+}
+
+use self::MalErr::{ErrLimit, ErrMalVal, ErrString};
+
+pub fn error<T>(s: &str) -> Result<T, MalErr> {
+    Err(ErrString(s.to_string()))
+}
+
+pub fn format_error(e: MalErr) -> String {
+    match e {
+        ErrString(s) => s,
+        ErrMalVal(v) => pr_str(&v, true),
+        ErrLimit(what) => format!("limit exceeded: {}", what),
+    }
+}
+
+pub fn func(f: fn(MalArgs) -> MalRet) -> Value {
+    Value::Func(Builtin {
+        f,
+        meta: Box::new(Value::Null),
+    })
+}
+
+pub fn keyword(name: &str) -> Value {
+    Value::String(format!("{}{}", KEYWORD_PREFIX, name))
+}
+
+pub fn atom(v: &Value) -> Value {
+    Value::Atom(Atom::new(v.clone()))
+}
+
+// With the "threads" feature values and envs go to other threads
+#[cfg(feature = "threads")]
+const _: fn() = || {
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<Value>();
+    send_sync::<Env>();
+};
+
+pub fn list(v: Vec<Value>) -> Value {
+    Value::List(v.into())
+}
+
+pub fn lazy_seq(state: Lazy) -> Value {
+    Value::Lazy(Shared::new(LazySeq(Lock::new(state))))
+}
+
+// Returns the key a value is kept under in a map. Only values that read
+// back as themselves can be keys.
+pub fn key_string(k: &Value) -> Result<String, MalErr> {
+    match k {
+        Value::String(_)
+        | Value::Number(_)
+        | Value::Char(_)
+        | Value::Boolean(_)
+        | Value::Symbol(_)
+        | Value::Null => Ok(map_key(k)),
+        _ => Err(ErrString(format!(
+            "{} cannot be a map key, keys are strings, keywords, numbers, chars or symbols",
+            pr_str(k, true)
+        ))),
+    }
+}
+
+// Returns the value a map key was made from
+pub fn key_value(k: &str) -> Value {
+    match read_str(k) {
+        Ok(Some(v)) => v,
+        _ => Value::String(k.to_string()),
+    }
+}
+
+pub fn hash_map(kvs: MalArgs) -> MalRet {
+    _assoc(HashMap::new(), kvs)
+}
+
+pub fn _assoc(mut hm: HashMap<String, Value>, kvs: MalArgs) -> MalRet {
+    if !kvs.len().is_multiple_of(2) {
+        return error("odd number of elements");
+    }
+    let mut kvs = kvs.into_iter();
+    while let (Some(k), Some(v)) = (kvs.next(), kvs.next()) {
+        hm.insert(key_string(&k)?, v);
+    }
+    Ok(Value::Map(hm))
+}
+
+pub fn _dissoc(mut hm: HashMap<String, Value>, ks: MalArgs) -> MalRet {
+    for k in ks.iter() {
+        hm.remove(&key_string(k)?);
+    }
+    Ok(Value::Map(hm))
+}
+
+impl Value {
+    // Returns the value without the metadata given by with-meta
+    pub fn strip(&self) -> &Value {
+        match self {
+            Value::Meta(v, _) => v,
+            v => v,
+        }
+    }
+
+    // Everything but nil and false is true in conditions
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Null | Value::Boolean(false))
+    }
+
+    // Equality as = sees it: lists equal vectors with the same elements,
+    // and metadata does not count
+    pub fn equals(&self, other: &Value) -> bool {
+        match (self.strip(), other.strip()) {
+            // Lazy seqs not realized all the way are only equal to themselves
+            (
+                Value::List(_) | Value::Vec(_) | Value::Lazy(_),
+                Value::List(_) | Value::Vec(_) | Value::Lazy(_),
+            ) => match (self.seq(), other.seq()) {
+                (Some(a), Some(b)) => {
+                    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.equals(y))
+                }
+                _ => self.strip() == other.strip(),
+            },
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len() && a.iter().all(|(k, v)| b.get(k).is_some_and(|w| v.equals(w)))
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    // Returns the value of the keyword :name in a map, records included
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match self.strip() {
+            Value::Map(hm) => hm.get(&format!(":{}", name)),
+            _ => None,
+        }
+    }
+
+    pub fn keyword_q(&self) -> bool {
+        matches!(self, Value::String(s) if s.starts_with(KEYWORD_PREFIX))
+    }
+
+    pub fn keyword(&self) -> MalRet {
+        match self {
+            Value::String(s) if s.starts_with(KEYWORD_PREFIX) => Ok(self.clone()),
+            Value::String(s) | Value::Symbol(s) => Ok(keyword(s)),
+            _ => error("keyword: expected a string"),
+        }
+    }
+
+    // Returns the elements of a list or vector, or of a lazy seq once it is
+    // realized all the way
+    pub fn seq(&self) -> Option<Vec<Value>> {
+        match self.strip() {
+            Value::List(l) => Some(l.iter().cloned().collect()),
+            Value::Vec(v) => Some(v.clone()),
+            Value::Lazy(s) => match s.realized(None) {
+                (elems, false) => Some(elems),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Returns the first element and the rest of a list, vector, lazy seq or
+    // nil, or None when it is empty. Lazy seqs are realized one element at a time.
+    pub fn uncons(&self) -> Result<Option<(Value, Value)>, MalErr> {
+        match self.strip() {
+            Value::Lazy(s) => s.realize(),
+            Value::List(l) => Ok(l.front().map(|first| {
+                (
+                    first.clone(),
+                    Value::List(l.iter().skip(1).cloned().collect()),
+                )
+            })),
+            Value::Vec(v) => Ok(v
+                .first()
+                .map(|first| (first.clone(), list(v[1..].to_vec())))),
+            Value::Null => Ok(None),
+            _ => error("expected a seq"),
+        }
+    }
+
+    pub fn apply(&self, args: MalArgs) -> MalRet {
+        match self {
+            Value::Func(b) => (b.f)(args),
+            Value::Closure(c) => {
+                let env = env_bind(Some(c.env.clone()), &c.params, args)?;
+                (c.eval)(c.body.clone(), env)
+            }
+            Value::Cont(k) => match args.len() {
+                0 | 1 => (k.resume)(k, args.into_iter().next().unwrap_or(Value::Null)),
+                n => error(&format!(
+                    "wrong number of arguments: expected 0 or 1, got {}",
+                    n
+                )),
+            },
+            _ => error("attempt to call non-function"),
+        }
+    }
+
+    pub fn deref(&self) -> MalRet {
+        match self {
+            Value::Atom(a) => Ok(a.0.borrow().clone()),
+            _ => error("attempt to deref a non-Atom"),
+        }
+    }
+
+    pub fn count(&self) -> MalRet {
+        match self.strip() {
+            Value::List(l) => Ok(Value::Number(l.len() as f64)),
+            Value::Vec(v) => Ok(Value::Number(v.len() as f64)),
+            Value::Map(m) => Ok(Value::Number(m.len() as f64)),
+            Value::Lazy(_) => match self.seq() {
+                Some(elems) => Ok(Value::Number(elems.len() as f64)),
+                None => error("count: the lazy seq is not realized"),
+            },
+            Value::String(s) if !self.keyword_q() => Ok(Value::Number(s.chars().count() as f64)),
+            Value::Null => Ok(Value::Number(0.0)),
+            _ => error("invalid type for count"),
+        }
+    }
+
+    pub fn empty_q(&self) -> MalRet {
+        match self.strip() {
+            Value::List(l) => Ok(Value::Boolean(l.is_empty())),
+            Value::Vec(v) => Ok(Value::Boolean(v.is_empty())),
+            Value::Map(m) => Ok(Value::Boolean(m.is_empty())),
+            Value::Lazy(_) => Ok(Value::Boolean(self.uncons()?.is_none())),
+            Value::Null => Ok(Value::Boolean(true)),
+            _ => error("invalid type for empty?"),
+        }
+    }
+
+    pub fn get_meta(&self) -> MalRet {
+        match self {
+            Value::Meta(_, meta) => Ok((**meta).clone()),
+            Value::Func(b) => Ok((*b.meta).clone()),
+            Value::Closure(c) => Ok(c.meta.clone()),
+            Value::List(_) | Value::Vec(_) | Value::Map(_) => Ok(Value::Null),
+            _ => error("meta not supported by type"),
+        }
+    }
+
+    pub fn with_meta(&self, meta: &Value) -> MalRet {
+        match self {
+            Value::Meta(v, _) => v.with_meta(meta),
+            Value::List(_) | Value::Vec(_) | Value::Map(_) => {
+                Ok(Value::Meta(Box::new(self.clone()), Box::new(meta.clone())))
+            }
+            Value::Func(b) => Ok(Value::Func(Builtin {
+                f: b.f,
+                meta: Box::new(meta.clone()),
+            })),
+            Value::Closure(c) => Ok(Value::Closure(Shared::new(Closure {
+                eval: c.eval,
+                params: c.params.clone(),
+                body: c.body.clone(),
+                env: c.env.clone(),
+                is_macro: c.is_macro,
+                meta: meta.clone(),
+            }))),
+            _ => error("with-meta not supported by type"),
+        }
+    }
+
+    pub fn pr_str(&self, readable: bool) -> String {
+        pr_str(self, readable)
+    }
+}